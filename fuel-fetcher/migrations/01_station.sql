@@ -0,0 +1,39 @@
+create table station (
+    state int not null,
+    id int not null,
+    brand text,
+    latitude real not null,
+    longitude real not null,
+    updated_at int not null,
+    primary key (state, id)
+);
+
+-- reference data from the QLD/SA FPD direct API
+create table fpd_reference (
+    state int primary key,
+    fetched_at int not null
+);
+
+create table fpd_fuel (
+    state int not null,
+    id int not null,
+    name text not null,
+    primary key (state, id)
+);
+
+create table fpd_brand (
+    state int not null,
+    id int not null,
+    name text not null,
+    primary key (state, id)
+);
+
+create table fpd_region (
+    state int not null,
+    level int not null,
+    id int not null,
+    name text not null,
+    abbrev text,
+    parent_id int,
+    primary key (state, level, id)
+);
@@ -0,0 +1,11 @@
+-- next-day prices, currently only published by WA
+create table price_forecast (
+    state int not null,
+    station int not null,
+    fuel int not null,
+    -- local date the price takes effect, YYYY-MM-DD
+    effective_date text not null,
+    fetched_at int not null,
+    price numeric not null,
+    primary key (state, station, fuel, effective_date)
+);
@@ -0,0 +1,7 @@
+alter table station add column location_flag text;
+
+-- catch what we can here, `check-stations` does the rest
+update station set latitude = longitude, longitude = latitude, location_flag = 'swapped'
+where latitude > 0 and longitude < 0;
+
+update station set location_flag = 'zero' where latitude = 0 and longitude = 0;
@@ -0,0 +1,4 @@
+-- from the nearest address point, cleared when the station moves
+alter table station add column address text;
+alter table station add column locality text;
+alter table station add column postcode text;
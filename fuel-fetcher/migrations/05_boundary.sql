@@ -0,0 +1,15 @@
+-- imported boundary polygons, geometry is GeoJSON in (longitude, latitude)
+create table boundary (
+    level text not null,
+    code text not null,
+    name text,
+    geometry text not null,
+    primary key (level, code)
+);
+
+-- codes of the boundaries each station is inside, cleared when the station moves
+alter table station add column sa2 text;
+alter table station add column sa3 text;
+alter table station add column sa4 text;
+alter table station add column lga text;
+alter table station add column poa text;
@@ -0,0 +1,2 @@
+-- for reading history by time, like the parquet export
+create index price_history_time on price_history (state, changed_at);
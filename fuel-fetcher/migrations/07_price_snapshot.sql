@@ -0,0 +1,14 @@
+-- lets the latest change per station and fuel before a time be found from the index
+drop index price_history_index;
+create index price_history_index on price_history (state, station, fuel, changed_at);
+
+-- the whole price board at `taken_at`, see board.rs
+create table price_snapshot (
+    taken_at int not null,
+    state int not null,
+    station int not null,
+    fuel int not null,
+    changed_at int not null,
+    price numeric,
+    primary key (taken_at, state, station, fuel)
+);
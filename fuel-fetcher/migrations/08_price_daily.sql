@@ -0,0 +1,16 @@
+-- daily price statistics weighted by how long each price was on the board,
+-- see aggregate.rs. `level` is 'state' with the state's name as `region`,
+-- or 'sa4' with the code
+create table price_daily (
+    date text not null,
+    state int not null,
+    level text not null,
+    region text not null,
+    fuel int not null,
+    min numeric not null,
+    mean numeric not null,
+    median numeric not null,
+    max numeric not null,
+    stations int not null,
+    primary key (date, state, level, region, fuel)
+);
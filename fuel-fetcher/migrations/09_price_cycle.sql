@@ -0,0 +1,29 @@
+-- turning points of retail price cycles in `price_daily` medians, see cycles.rs
+create table price_cycle_point (
+    state int not null,
+    level text not null,
+    region text not null,
+    fuel int not null,
+    date text not null,
+    kind text not null,
+    price numeric not null,
+    primary key (state, level, region, fuel, date)
+);
+
+-- the recent cycles of each region and fuel, length in days and amplitude in
+-- cents per liter are medians, phase is 'rising' after a trough or 'falling'
+-- after a peak
+create table price_cycle (
+    state int not null,
+    level text not null,
+    region text not null,
+    fuel int not null,
+    updated_at int not null,
+    cycles int not null,
+    length numeric,
+    amplitude numeric,
+    phase text,
+    last_trough text,
+    last_peak text,
+    primary key (state, level, region, fuel)
+);
@@ -0,0 +1,10 @@
+-- alerts that have been sent and are still matching, removed once they stop
+-- matching so they can be sent again, see alerts.rs
+create table alert (
+    rule text not null,
+    key text not null,
+    -- each sink is recorded separately, so one failing doesn't resend to the rest
+    sink text not null,
+    sent_at int not null,
+    primary key (rule, key, sink)
+);
@@ -0,0 +1,47 @@
+-- every price change and station edit in order, `id` is the cursor for
+-- syncing, see changes.rs. Kept up to date by triggers so every writer is
+-- covered.
+--
+-- Price changes are copied from price_history because one cursor has to order
+-- them with station edits, and the two tables' rowids aren't one sequence. To
+-- keep the copy small it isn't backfilled, so the log starts at this migration
+-- and older changes are only in price_history.
+create table change_log (
+    id integer primary key autoincrement,
+    -- 'price' or 'station'
+    kind text not null,
+    state int not null,
+    station int not null,
+    fuel int,
+    price numeric,
+    changed_at int not null
+);
+
+create trigger change_log_price after insert on price_history begin
+    insert into change_log (kind, state, station, fuel, price, changed_at)
+    values ('price', new.state, new.station, new.fuel, new.price, new.changed_at);
+end;
+
+create trigger change_log_station_insert after insert on station begin
+    insert into change_log (kind, state, station, changed_at)
+    values ('station', new.state, new.id, new.updated_at);
+end;
+
+-- not when only updated_at changes, the stations are upserted every fetch
+create trigger change_log_station_update after update on station
+when old.brand is not new.brand
+    or old.latitude is not new.latitude
+    or old.longitude is not new.longitude
+    or old.location_flag is not new.location_flag
+    or old.address is not new.address
+    or old.locality is not new.locality
+    or old.postcode is not new.postcode
+    or old.sa2 is not new.sa2
+    or old.sa3 is not new.sa3
+    or old.sa4 is not new.sa4
+    or old.lga is not new.lga
+    or old.poa is not new.poa
+begin
+    insert into change_log (kind, state, station, changed_at)
+    values ('station', new.state, new.id, cast(strftime('%s', 'now') as int));
+end;
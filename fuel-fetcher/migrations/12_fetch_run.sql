@@ -0,0 +1,18 @@
+-- one row per source per `prices` run, for finding out what happened later
+create table fetch_run (
+    id integer primary key autoincrement,
+    -- the state fetched
+    source int not null,
+    started_at int not null,
+    finished_at int not null,
+    http_attempts int not null,
+    -- null if the fetch failed
+    rows_parsed int,
+    -- prices that changed from a previous one, not counting new ones, like the
+    -- count `prices` prints. All rows written are in price_history by run_id
+    changes int not null,
+    error text
+);
+
+-- the run that recorded the change, null for ones from before runs were kept
+alter table price_history add column run_id int references fetch_run (id);
@@ -0,0 +1,20 @@
+-- prices are only written when they change, so when they were last seen is
+-- tracked per source instead of rewriting `updated_at` on every row each run
+
+-- the latest successful fetch of each state
+create table source_seen (
+    state int primary key,
+    seen_at int not null
+);
+
+-- null while the source still lists the price, otherwise when it last did
+alter table price add column last_seen int;
+
+-- `price` with `updated_at` as when it was last seen, like before, and
+-- `last_seen` set if it's no longer listed
+create view current_price as
+select state, station, fuel, price,
+    coalesce(last_seen, max(updated_at, coalesce(seen_at, 0))) as updated_at,
+    last_seen
+from price
+left join source_seen using (state);
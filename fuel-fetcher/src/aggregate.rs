@@ -0,0 +1,300 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use chrono::{DateTime, Days, NaiveDate};
+use rusqlite::Connection;
+
+use crate::{board, State};
+
+/// Statistics for one fuel in a region over a local day.
+struct Daily {
+    level: &'static str,
+    region: String,
+    fuel: u8,
+    min: f64,
+    mean: f64,
+    median: f64,
+    max: f64,
+    stations: usize,
+}
+
+/// Brings `price_daily` up to date, redoing the latest day of each state
+/// since it was probably partial. Returns how many days were written.
+pub fn update(conn: &Connection) -> Result<usize> {
+    let mut days = 0;
+    for state in State::all() {
+        let latest: Option<String> = conn.query_row(
+            "select max(date) from price_daily where state = ?",
+            [state as u8],
+            |row| row.get(0),
+        )?;
+        let from = match latest {
+            Some(x) => x.parse()?,
+            None => {
+                let first: Option<u64> = conn.query_row(
+                    "select min(changed_at) from price_history where state = ?",
+                    [state as u8],
+                    |row| row.get(0),
+                )?;
+                match first {
+                    Some(x) => local_date(state, x),
+                    None => continue,
+                }
+            }
+        };
+        days += update_state(conn, state, from)?;
+    }
+    Ok(days)
+}
+
+/// Recomputes all of `price_daily` from `price_history`.
+pub fn rebuild(conn: &Connection) -> Result<usize> {
+    conn.execute("delete from price_daily", [])?;
+    update(conn)
+}
+
+fn update_state(conn: &Connection, state: State, from: NaiveDate) -> Result<usize> {
+    let now = crate::now();
+    let today = local_date(state, now);
+
+    let mut regions = HashMap::new();
+    {
+        let mut select =
+            conn.prepare("select id, sa4 from station where state = ? and sa4 is not null")?;
+        let mut rows = select.query([state as u8])?;
+        while let Some(row) = rows.next()? {
+            regions.insert(row.get::<_, u32>(0)?, row.get::<_, String>(1)?);
+        }
+    }
+
+    // (station, fuel) to when the source stopped listing it, its price is only
+    // on the board until then
+    let mut delisted: HashMap<(u32, u8), u64> = HashMap::new();
+    {
+        let mut select = conn.prepare(
+            "select station, fuel, last_seen from price where state = ? and last_seen is not null",
+        )?;
+        let mut rows = select.query([state as u8])?;
+        while let Some(row) = rows.next()? {
+            delisted.insert((row.get(0)?, row.get(1)?), row.get(2)?);
+        }
+    }
+
+    // (station, fuel) to (price, since), carried from one day to the next
+    let mut board: HashMap<(u32, u8), (Option<f64>, u64)> =
+        board::at(conn, day_start(state, from), Some(state), None)?
+            .into_iter()
+            .map(|x| ((x.station, x.fuel as u8), (x.price, x.changed_at)))
+            .collect();
+
+    let mut changes = conn.prepare(
+        "select station, fuel, price, changed_at from price_history
+        where state = ? and changed_at > ? and changed_at <= ?
+        order by changed_at, rowid",
+    )?;
+
+    let mut days = 0;
+    let mut date = from;
+    while date <= today {
+        let start = day_start(state, date);
+        let end = day_start(state, date + Days::new(1)).min(now);
+
+        // (fuel, station) to the seconds each price was on the board that day
+        let mut seen: HashMap<(u8, u32), Vec<(f64, u64)>> = HashMap::new();
+        let mut close = |key: (u32, u8), price: Option<f64>, since: u64, until: u64| {
+            let since = since.max(start);
+            let until = delisted.get(&key).map_or(until, |x| until.min(*x));
+            if let Some(price) = price {
+                if until > since {
+                    seen.entry((key.1, key.0))
+                        .or_default()
+                        .push((price, until - since));
+                }
+            }
+        };
+
+        let mut rows = changes.query((state as u8, start, end))?;
+        while let Some(row) = rows.next()? {
+            let key: (u32, u8) = (row.get(0)?, row.get(1)?);
+            let price: Option<f64> = row.get(2)?;
+            let changed_at: u64 = row.get(3)?;
+            if let Some((old, since)) = board.insert(key, (price, changed_at)) {
+                close(key, old, since, changed_at);
+            }
+        }
+        for (key, (price, since)) in &board {
+            close(*key, *price, *since, end);
+        }
+
+        write(conn, state, date, &summarise(state, &seen, &regions))?;
+        days += 1;
+        date = date + Days::new(1);
+    }
+    Ok(days)
+}
+
+// (level, region, fuel)
+type Group = (&'static str, String, u8);
+
+fn summarise(
+    state: State,
+    seen: &HashMap<(u8, u32), Vec<(f64, u64)>>,
+    regions: &HashMap<u32, String>,
+) -> Vec<Daily> {
+    // every station's prices and how long they were on the board
+    let mut groups: HashMap<Group, Vec<(u32, f64, u64)>> = HashMap::new();
+    for ((fuel, station), prices) in seen {
+        let mut keys = vec![("state", state.as_str().to_string())];
+        if let Some(x) = regions.get(station) {
+            keys.push(("sa4", x.clone()));
+        }
+        for (level, region) in keys {
+            let group = groups.entry((level, region, *fuel)).or_default();
+            group.extend(
+                prices
+                    .iter()
+                    .map(|(price, seconds)| (*station, *price, *seconds)),
+            );
+        }
+    }
+
+    let mut daily = Vec::new();
+    for ((level, region, fuel), mut prices) in groups {
+        prices.sort_by(|a, b| a.1.total_cmp(&b.1));
+        let total: u64 = prices.iter().map(|x| x.2).sum();
+        let mean = prices.iter().map(|x| x.1 * x.2 as f64).sum::<f64>() / total as f64;
+        // the price that was on the board at the middle of the day's station-seconds
+        let mut seconds = 0;
+        let median = prices
+            .iter()
+            .find(|x| {
+                seconds += x.2;
+                seconds * 2 >= total
+            })
+            .map_or(mean, |x| x.1);
+        let stations: HashSet<u32> = prices.iter().map(|x| x.0).collect();
+        daily.push(Daily {
+            level,
+            region,
+            fuel,
+            min: prices[0].1,
+            mean,
+            median,
+            max: prices[prices.len() - 1].1,
+            stations: stations.len(),
+        });
+    }
+    daily
+}
+
+fn write(conn: &Connection, state: State, date: NaiveDate, daily: &[Daily]) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    {
+        tx.execute(
+            "delete from price_daily where date = ? and state = ?",
+            (date, state as u8),
+        )?;
+        let mut insert = tx.prepare(
+            "insert into price_daily (date, state, level, region, fuel, min, mean, median, max, stations)
+            values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )?;
+        for x in daily {
+            insert.execute((
+                date,
+                state as u8,
+                x.level,
+                &x.region,
+                x.fuel,
+                x.min,
+                x.mean,
+                x.median,
+                x.max,
+                x.stations,
+            ))?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn local_date(state: State, timestamp: u64) -> NaiveDate {
+    DateTime::from_timestamp(timestamp as i64, 0)
+        .expect("timestamp in range")
+        .with_timezone(&state.utc_offset())
+        .date_naive()
+}
+
+/// When `date` starts in the state, in unix seconds.
+pub fn day_start(state: State, date: NaiveDate) -> u64 {
+    date.and_time(Default::default())
+        .and_local_timezone(state.utc_offset())
+        .single()
+        .expect("fixed offsets are never ambiguous")
+        .timestamp() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db, Fuel};
+
+    #[test]
+    fn delisted_prices_leave_the_board() {
+        let conn = db::open_in_memory().unwrap();
+        let state = State::NSW;
+        let date = local_date(state, crate::now()) - Days::new(1);
+        let start = day_start(state, date);
+        for (station, price) in [(1, 100.0), (2, 200.0)] {
+            conn.execute(
+                "insert into price_history (state, station, fuel, changed_at, price)
+                values (?, ?, ?, ?, ?)",
+                (
+                    state as u8,
+                    station,
+                    Fuel::Unleaded91 as u8,
+                    start - 3600,
+                    price,
+                ),
+            )
+            .unwrap();
+        }
+        // station 2 dropped out of the source at midday
+        conn.execute(
+            "insert into price (state, station, fuel, updated_at, price, last_seen)
+            values (?1, 1, ?2, ?3, 100.0, null), (?1, 2, ?2, ?3, 200.0, ?4)",
+            (
+                state as u8,
+                Fuel::Unleaded91 as u8,
+                start - 3600,
+                start + 12 * 60 * 60,
+            ),
+        )
+        .unwrap();
+
+        update_state(&conn, state, date).unwrap();
+        let (mean, median, max, stations): (f64, f64, f64, usize) = conn
+            .query_row(
+                "select mean, median, max, stations from price_daily
+                where date = ? and level = 'state' and fuel = ?",
+                (date, Fuel::Unleaded91 as u8),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        // a full day at 100 and half a day at 200
+        assert!((mean - 400.0 / 3.0).abs() < 1e-9, "{mean}");
+        assert_eq!(median, 100.0);
+        assert_eq!(max, 200.0);
+        assert_eq!(stations, 2);
+
+        // gone for the whole of today
+        update_state(&conn, state, date + Days::new(1)).unwrap();
+        let max: f64 = conn
+            .query_row(
+                "select max from price_daily where date = ? and level = 'state' and fuel = ?",
+                (date + Days::new(1), Fuel::Unleaded91 as u8),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(max, 100.0);
+    }
+}
@@ -0,0 +1,571 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use chrono::DateTime;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use ureq::{Agent, AgentBuilder};
+
+use crate::{board, boundary::Level, Fuel, State};
+
+// how long a webhook has to respond, so one that hangs doesn't hold up the run
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Rules and where to send their alerts, from a TOML file like:
+///
+/// ```toml
+/// [[sink]]
+/// type = "webhook"
+/// url = "http://localhost:8123/api/webhook/fuel"
+///
+/// [[rule]]
+/// name = "cheap u91"
+/// kind = "below"
+/// state = "NSW"
+/// stations = [1234, 5678]
+/// fuel = "Unleaded91"
+/// price = 180.0
+///
+/// [[rule]]
+/// name = "sydney hike"
+/// kind = "median_rise"
+/// state = "NSW"
+/// level = "sa4"   # optional with region, the whole state otherwise
+/// region = "117"
+/// fuel = "Unleaded91"
+/// rise = 10.0
+///
+/// [[rule]]
+/// name = "no diesel"
+/// kind = "out_of_stock"
+/// state = "NSW"
+/// stations = [1234]
+/// fuel = "Diesel" # optional, any fuel otherwise
+/// ```
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(default, rename = "sink")]
+    pub sinks: Vec<Sink>,
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path)?;
+        toml::from_str(&data).with_context(|| format!("invalid alert rules in {path:?}"))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Sink {
+    Stdout,
+    /// Appends a line per alert
+    File {
+        path: PathBuf,
+    },
+    /// Posts each alert as json
+    Webhook {
+        url: String,
+    },
+}
+
+#[derive(Deserialize)]
+pub struct Rule {
+    pub name: String,
+    #[serde(flatten)]
+    pub condition: Condition,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Condition {
+    /// Any of the stations selling `fuel` for less than `price`
+    Below {
+        state: State,
+        stations: Vec<u32>,
+        fuel: Fuel,
+        // cents per liter
+        price: f64,
+    },
+    /// The median price in a region going up by more than `rise` in 24 hours
+    MedianRise {
+        state: State,
+        level: Option<Level>,
+        region: Option<String>,
+        fuel: Fuel,
+        // cents per liter
+        rise: f64,
+    },
+    /// Any of the stations no longer selling `fuel`, or any fuel
+    OutOfStock {
+        state: State,
+        stations: Vec<u32>,
+        fuel: Option<Fuel>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct Alert {
+    pub rule: String,
+    /// What matched, the same each run while it still matches
+    pub key: String,
+    pub message: String,
+}
+
+/// Checks every rule against the database and sends alerts that weren't
+/// already sent while they kept matching. Returns how many were sent.
+pub fn run(conn: &Connection, config: &Config) -> Result<usize> {
+    let mut sent = 0;
+    for rule in &config.rules {
+        let alerts = evaluate(conn, rule).with_context(|| format!("alert rule {}", rule.name))?;
+        sent += notify(conn, &rule.name, alerts, &config.sinks)?;
+    }
+    Ok(sent)
+}
+
+fn evaluate(conn: &Connection, rule: &Rule) -> Result<Vec<Alert>> {
+    let alert = |key: String, message: String| Alert {
+        rule: rule.name.clone(),
+        key,
+        message,
+    };
+    let mut alerts = Vec::new();
+    match &rule.condition {
+        Condition::Below {
+            state,
+            stations,
+            fuel,
+            price,
+        } => {
+            let mut select = conn.prepare(
+                "select price from current_price
+                where state = ? and station = ? and fuel = ? and price < ? and last_seen is null",
+            )?;
+            for station in stations {
+                let mut rows = select.query((*state as u8, station, *fuel as u8, price))?;
+                if let Some(row) = rows.next()? {
+                    let current: f64 = row.get(0)?;
+                    alerts.push(alert(
+                        station.to_string(),
+                        format!(
+                            "{} at {} {station} is {current:.1}, below {price:.1}",
+                            fuel.as_str(),
+                            state.as_str()
+                        ),
+                    ));
+                }
+            }
+        }
+
+        Condition::MedianRise {
+            state,
+            level,
+            region,
+            fuel,
+            rise,
+        } => {
+            let stations = match (level, region) {
+                (Some(level), Some(region)) => {
+                    let mut select = conn.prepare(&format!(
+                        "select id from station where state = ? and {} = ?",
+                        level.as_str()
+                    ))?;
+                    let rows = select.query_map((*state as u8, region), |row| row.get(0))?;
+                    Some(rows.collect::<rusqlite::Result<HashSet<u32>>>()?)
+                }
+                _ => None,
+            };
+            let median = |time: u64| -> Result<Option<f64>> {
+                let mut prices: Vec<f64> = board::at(conn, time, Some(*state), Some(*fuel))?
+                    .into_iter()
+                    .filter(|x| stations.as_ref().is_none_or(|s| s.contains(&x.station)))
+                    .filter_map(|x| x.price)
+                    .collect();
+                prices.sort_by(f64::total_cmp);
+                Ok(prices.get(prices.len() / 2).copied())
+            };
+
+            let now = crate::now();
+            if let (Some(before), Some(after)) = (median(now - 24 * 60 * 60)?, median(now)?) {
+                if after - before > *rise {
+                    let name = region.as_deref().unwrap_or(state.as_str());
+                    // the day it happened, so a rise that lasts isn't sent again
+                    let day = DateTime::from_timestamp(now as i64, 0)
+                        .expect("now is in range")
+                        .with_timezone(&state.utc_offset())
+                        .date_naive();
+                    alerts.push(alert(
+                        day.to_string(),
+                        format!(
+                            "median {} in {name} rose {:.1} to {after:.1} in 24 hours",
+                            fuel.as_str(),
+                            after - before
+                        ),
+                    ));
+                }
+            }
+        }
+
+        Condition::OutOfStock {
+            state,
+            stations,
+            fuel,
+        } => {
+            // a fuel the source stopped listing isn't being sold either
+            let mut select = conn.prepare(
+                "select fuel, last_seen is not null from current_price
+                where state = ? and station = ? and (?3 is null or fuel = ?3)
+                    and (price is null or last_seen is not null)",
+            )?;
+            for station in stations {
+                let rows = select
+                    .query_map((*state as u8, station, fuel.map(|x| x as u8)), |row| {
+                        Ok((row.get::<_, Fuel>(0)?, row.get::<_, bool>(1)?))
+                    })?;
+                for x in rows {
+                    let (x, delisted) = x?;
+                    let message = if delisted {
+                        format!(
+                            "{} {station} no longer lists {}",
+                            state.as_str(),
+                            x.as_str()
+                        )
+                    } else {
+                        format!("{} {station} is out of {}", state.as_str(), x.as_str())
+                    };
+                    alerts.push(alert(format!("{station} {}", x.as_str()), message));
+                }
+            }
+        }
+    }
+    Ok(alerts)
+}
+
+/// Sends the alerts not already sent for `rule` to each sink and forgets the
+/// ones that stopped matching. An alert that fails to send is tried again next
+/// time, only to the sinks it failed on. Returns how many alerts reached a sink.
+fn notify(conn: &Connection, rule: &str, alerts: Vec<Alert>, sinks: &[Sink]) -> Result<usize> {
+    // key to the sinks it was sent to
+    let mut previous: HashMap<String, HashSet<String>> = HashMap::new();
+    {
+        let mut select = conn.prepare("select key, sink from alert where rule = ?")?;
+        let mut rows = select.query([rule])?;
+        while let Some(row) = rows.next()? {
+            previous.entry(row.get(0)?).or_default().insert(row.get(1)?);
+        }
+    }
+
+    let now = crate::now();
+    let mut sent = 0;
+    let mut matching = HashSet::new();
+    for alert in alerts {
+        matching.insert(alert.key.clone());
+        let done = previous.get(&alert.key);
+        let mut delivered = false;
+        for sink in sinks {
+            let id = sink.id();
+            if done.is_some_and(|x| x.contains(&id)) {
+                continue;
+            }
+            if let Err(e) = sink.send(&alert) {
+                eprintln!("Failed to send alert {} to {id}: {e:#}", alert.rule);
+                continue;
+            }
+            conn.execute(
+                "insert into alert (rule, key, sink, sent_at) values (?, ?, ?, ?)",
+                (rule, &alert.key, &id, now),
+            )?;
+            delivered = true;
+        }
+        if delivered {
+            sent += 1;
+        }
+    }
+
+    for key in previous.keys().filter(|x| !matching.contains(*x)) {
+        conn.execute("delete from alert where rule = ? and key = ?", (rule, key))?;
+    }
+    Ok(sent)
+}
+
+impl Sink {
+    /// What the sink is recorded as in `alert`.
+    fn id(&self) -> String {
+        match self {
+            Self::Stdout => "stdout".into(),
+            Self::File { path } => format!("file {}", path.display()),
+            Self::Webhook { url } => format!("webhook {url}"),
+        }
+    }
+
+    pub fn send(&self, alert: &Alert) -> Result<()> {
+        match self {
+            Self::Stdout => println!("{}: {}", alert.rule, alert.message),
+            Self::File { path } => {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                let time = chrono::Utc::now().to_rfc3339();
+                writeln!(file, "{time} {}: {}", alert.rule, alert.message)?;
+            }
+            Self::Webhook { url } => {
+                agent().post(url).send_json(alert)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// not the fetchers' agent, alerts aren't fetch attempts and need a timeout
+fn agent() -> Agent {
+    AgentBuilder::new()
+        .user_agent(crate::USER_AGENT)
+        .timeout(TIMEOUT)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read},
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+    };
+
+    use super::*;
+
+    fn alert(key: &str) -> Alert {
+        Alert {
+            rule: "cheap u91".into(),
+            key: key.into(),
+            message: "Unleaded91 at NSW 1 is 179.9, below 180.0".into(),
+        }
+    }
+
+    /// Accepts `count` requests, sending back each body.
+    fn listen(count: usize) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(count) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                    .unwrap();
+                tx.send(String::from_utf8(body).unwrap()).unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    fn conn() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../migrations/10_alert.sql"))
+            .unwrap();
+        conn
+    }
+
+    #[test]
+    fn webhook() {
+        let (url, rx) = listen(1);
+        Sink::Webhook { url }.send(&alert("1")).unwrap();
+        let body: serde_json::Value = serde_json::from_str(&rx.recv().unwrap()).unwrap();
+        assert_eq!(body["rule"], "cheap u91");
+        assert_eq!(body["key"], "1");
+        assert_eq!(body["message"], "Unleaded91 at NSW 1 is 179.9, below 180.0");
+    }
+
+    #[test]
+    fn sent_once_while_matching() {
+        let (url, rx) = listen(2);
+        let sinks = [Sink::Webhook { url }];
+        let conn = conn();
+
+        assert_eq!(
+            notify(&conn, "cheap u91", vec![alert("1")], &sinks).unwrap(),
+            1
+        );
+        assert_eq!(
+            notify(&conn, "cheap u91", vec![alert("1")], &sinks).unwrap(),
+            0
+        );
+        // stops matching, then matches again
+        assert_eq!(notify(&conn, "cheap u91", vec![], &sinks).unwrap(), 0);
+        assert_eq!(
+            notify(&conn, "cheap u91", vec![alert("1")], &sinks).unwrap(),
+            1
+        );
+
+        assert_eq!(rx.iter().count(), 2);
+    }
+
+    #[test]
+    fn failed_webhook_is_retried() {
+        // nothing listening
+        let url = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/hook", listener.local_addr().unwrap())
+        };
+        let sinks = [Sink::Webhook { url }];
+        let conn = conn();
+
+        assert_eq!(
+            notify(&conn, "cheap u91", vec![alert("1")], &sinks).unwrap(),
+            0
+        );
+        let count: usize = conn
+            .query_row("select count(*) from alert", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn failed_sink_is_retried_alone() {
+        let dead = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/hook", listener.local_addr().unwrap())
+        };
+        let path = std::env::temp_dir().join(format!("fuel-alerts-{}.log", std::process::id()));
+        let sinks = [
+            Sink::File { path: path.clone() },
+            Sink::Webhook { url: dead },
+        ];
+        let conn = conn();
+
+        // the file gets it the first time, the webhook fails both times
+        for expected in [1, 0] {
+            assert_eq!(
+                notify(&conn, "cheap u91", vec![alert("1")], &sinks).unwrap(),
+                expected
+            );
+        }
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(lines, 1);
+        let sent: Vec<String> = conn
+            .prepare("select sink from alert")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(sent, [format!("file {}", path.display())]);
+    }
+
+    #[test]
+    fn delisted_prices() {
+        let conn = crate::db::open_in_memory().unwrap();
+        conn.execute(
+            "insert into price (state, station, fuel, updated_at, price, last_seen)
+            values (?1, 1, ?2, 100, 170.0, null), (?1, 2, ?2, 100, 170.0, 200),
+                (?1, 3, ?2, 100, null, null)",
+            (State::NSW as u8, Fuel::Unleaded91 as u8),
+        )
+        .unwrap();
+        let keys = |condition| -> Vec<String> {
+            let rule = Rule {
+                name: "rule".into(),
+                condition,
+            };
+            evaluate(&conn, &rule)
+                .unwrap()
+                .into_iter()
+                .map(|x| x.key)
+                .collect()
+        };
+
+        // 2 is still cheap but no longer listed
+        let below = Condition::Below {
+            state: State::NSW,
+            stations: vec![1, 2, 3],
+            fuel: Fuel::Unleaded91,
+            price: 180.0,
+        };
+        assert_eq!(keys(below), ["1"]);
+
+        let out = Condition::OutOfStock {
+            state: State::NSW,
+            stations: vec![1, 2, 3],
+            fuel: None,
+        };
+        assert_eq!(keys(out), ["2 Unleaded91", "3 Unleaded91"]);
+    }
+
+    #[test]
+    fn config() {
+        let config: Config = toml::from_str(
+            r#"
+            [[sink]]
+            type = "stdout"
+
+            [[sink]]
+            type = "file"
+            path = "alerts.log"
+
+            [[rule]]
+            name = "cheap u91"
+            kind = "below"
+            state = "NSW"
+            stations = [1, 2]
+            fuel = "Unleaded91"
+            price = 180.0
+
+            [[rule]]
+            name = "hike"
+            kind = "median_rise"
+            state = "NSW"
+            level = "sa4"
+            region = "117"
+            fuel = "Unleaded91"
+            rise = 10.0
+
+            [[rule]]
+            name = "no diesel"
+            kind = "out_of_stock"
+            state = "QLD"
+            stations = [3]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.sinks.len(), 2);
+        assert!(matches!(
+            config.rules[0].condition,
+            Condition::Below { price, .. } if price == 180.0
+        ));
+        assert!(matches!(
+            config.rules[1].condition,
+            Condition::MedianRise {
+                level: Some(Level::Sa4),
+                ..
+            }
+        ));
+        assert!(matches!(
+            config.rules[2].condition,
+            Condition::OutOfStock { fuel: None, .. }
+        ));
+    }
+}
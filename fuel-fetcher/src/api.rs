@@ -0,0 +1,546 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use geo::Point;
+use rusqlite::{Connection, OptionalExtension, Row};
+use serde::Serialize;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{
+    changes::{self, Change, Filter},
+    cycles::{self, Cycle},
+    db,
+    nearest::{Nearby, StationIndex},
+    recommend::{self, Recommendation},
+    Fuel, State,
+};
+
+/// Serves a read-only json API over `fuel.db`:
+///
+/// - `GET /prices?state=&fuel=&station=` current prices, all filters optional,
+///   and the change log cursor they're as of
+/// - `GET /stations?state=` stations and the change log cursor they're as of
+/// - `GET /stations/{state}/{id}` station details and current prices
+/// - `GET /history/{state}/{station}/{fuel}?from=&to=` price changes, times are
+///   unix seconds or RFC 3339
+/// - `GET /cheapest?fuel=&lat=&lon=&radius=&detour_cost=&limit=` cheapest
+///   stations within `radius` km (default 10), `limit` results (default 10), see
+///   [`StationIndex::nearest`]
+/// - `GET /cycles?state=&fuel=&level=` price cycles, see [`cycles`]
+/// - `GET /recommend?fuel=&lat=&lon=&radius=` whether to fill up now or wait,
+///   see [`recommend::recommend`]
+/// - `GET /changes?since=&limit=` price and station changes after a cursor
+///   (default 0), up to `limit` (default 1000) along with the cursor to pass next.
+///   The log only starts when it was added, so to sync from scratch get
+///   `/stations` and `/prices` first, then follow from the older of their two
+///   cursors; changes already in a snapshot are repeated, never missed
+/// - `GET /stream?state=&fuel=&bbox=&since=` server-sent events of price changes
+///   as they're stored, `bbox` is `min_lon,min_lat,max_lon,max_lat`. Starts
+///   from now unless given a cursor in `since` or `Last-Event-ID`. At most
+///   [`MAX_STREAMS`] at once, more get a 503
+pub fn serve(listen: &str) -> anyhow::Result<()> {
+    let server = Server::http(listen).map_err(|e| anyhow!(e))?;
+    let conn = db::open()?;
+    let mut index = None;
+    let streams = Arc::new(AtomicUsize::new(0));
+    eprintln!("Listening on {listen}");
+
+    for request in server.incoming_requests() {
+        let (path, query) = split_url(request.url());
+        if *request.method() == Method::Get && path == "/stream" {
+            let Some(slot) = StreamSlot::take(&streams) else {
+                respond(request, Err(Error::Unavailable("too many streams".into())));
+                continue;
+            };
+            match stream_filter(&conn, &request, &query) {
+                Ok((since, filter)) => {
+                    thread::spawn(move || {
+                        let _slot = slot;
+                        // usually just the client going away
+                        if let Err(e) = stream(request, since, filter) {
+                            eprintln!("Stream ended: {e:#}");
+                        }
+                    });
+                }
+                Err(e) => respond(request, Err(e)),
+            }
+            continue;
+        }
+
+        let result = handle(&conn, &mut index, &request);
+        respond(request, result);
+    }
+
+    Ok(())
+}
+
+fn respond(request: Request, result: Result<Vec<u8>>) {
+    let (status, body) = match result {
+        Ok(x) => (200, x),
+        Err(Error::BadRequest(e)) => (400, error(e)),
+        Err(Error::NotFound) => (404, error("not found".into())),
+        Err(Error::Unavailable(e)) => (503, error(e)),
+        Err(Error::Internal(e)) => {
+            eprintln!("{} {} failed: {e:#}", request.method(), request.url());
+            (500, error("internal error".into()))
+        }
+    };
+    let header = Header::from_bytes("Content-Type", "application/json").expect("hardcoded header");
+    let response = Response::from_data(body)
+        .with_status_code(status)
+        .with_header(header);
+    if let Err(e) = request.respond(response) {
+        eprintln!("Failed to respond: {e}");
+    }
+}
+
+enum Error {
+    BadRequest(String),
+    NotFound,
+    Unavailable(String),
+    Internal(anyhow::Error),
+}
+
+impl<E: Into<anyhow::Error>> From<E> for Error {
+    fn from(value: E) -> Self {
+        Self::Internal(value.into())
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn error(message: String) -> Vec<u8> {
+    serde_json::to_vec(&serde_json::json!({ "error": message })).expect("valid json")
+}
+
+/// The station index, kept between requests until prices or stations change.
+struct CachedIndex {
+    index: StationIndex,
+    version: (i64, u64),
+}
+
+fn station_index<'a>(
+    conn: &Connection,
+    cache: &'a mut Option<CachedIndex>,
+) -> Result<&'a StationIndex> {
+    // prices that didn't change are still updated when their source is seen
+    let version = (
+        changes::latest(conn)?,
+        conn.query_row(
+            "select coalesce(max(seen_at), 0) from source_seen",
+            [],
+            |row| row.get(0),
+        )?,
+    );
+    if cache.as_ref().map(|x| x.version) != Some(version) {
+        *cache = Some(CachedIndex {
+            index: StationIndex::load(conn)?,
+            version,
+        });
+    }
+    Ok(&cache.as_ref().expect("just loaded").index)
+}
+
+fn handle(
+    conn: &Connection,
+    index: &mut Option<CachedIndex>,
+    request: &Request,
+) -> Result<Vec<u8>> {
+    if *request.method() != Method::Get {
+        return Err(Error::NotFound);
+    }
+    let (path, query) = split_url(request.url());
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    let body = match &*segments {
+        ["prices"] => serde_json::to_vec(&prices(conn, &query)?),
+        ["stations"] => serde_json::to_vec(&stations(conn, &query)?),
+        ["stations", state, id] => serde_json::to_vec(&station(conn, parse(state)?, parse(id)?)?),
+        ["history", state, station, fuel] => serde_json::to_vec(&history(
+            conn,
+            parse(state)?,
+            parse(station)?,
+            parse(fuel)?,
+            &query,
+        )?),
+        ["cheapest"] => serde_json::to_vec(&cheapest(station_index(conn, index)?, &query)?),
+        ["cycles"] => serde_json::to_vec(&cycles(conn, &query)?),
+        ["recommend"] => {
+            serde_json::to_vec(&recommendation(conn, station_index(conn, index)?, &query)?)
+        }
+        ["changes"] => serde_json::to_vec(&change_feed(conn, &query)?),
+        _ => return Err(Error::NotFound),
+    }?;
+    Ok(body)
+}
+
+fn split_url(url: &str) -> (&str, Query) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let query = Query(
+        form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect(),
+    );
+    (path, query)
+}
+
+struct Query(HashMap<String, String>);
+
+impl Query {
+    fn get<T: std::str::FromStr>(&self, key: &str) -> Result<Option<T>> {
+        self.0.get(key).map(|x| parse(x)).transpose()
+    }
+
+    fn time(&self, key: &str) -> Result<Option<u64>> {
+        self.0
+            .get(key)
+            .map(|x| crate::parse_time(x).map_err(|e| Error::BadRequest(format!("{e}"))))
+            .transpose()
+    }
+
+    fn require<T: std::str::FromStr>(&self, key: &str) -> Result<T> {
+        self.get(key)?
+            .ok_or_else(|| Error::BadRequest(format!("missing {key}")))
+    }
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| Error::BadRequest(format!("invalid value {value:?}")))
+}
+
+#[derive(Serialize)]
+struct CurrentPrice {
+    state: State,
+    station: u32,
+    fuel: Fuel,
+    price: Option<f64>,
+    updated_at: u64,
+}
+
+impl CurrentPrice {
+    const COLUMNS: &'static str = "state, station, fuel, price, updated_at";
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            state: row.get(0)?,
+            station: row.get(1)?,
+            fuel: row.get(2)?,
+            price: row.get(3)?,
+            updated_at: row.get(4)?,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct Prices {
+    prices: Vec<CurrentPrice>,
+    /// The latest change included, pass as `since` to `/changes` to follow on
+    cursor: i64,
+}
+
+fn prices(conn: &Connection, query: &Query) -> Result<Prices> {
+    let state: Option<State> = query.get("state")?;
+    let fuel: Option<Fuel> = query.get("fuel")?;
+    let station: Option<u32> = query.get("station")?;
+
+    // read together so the cursor matches the prices
+    let tx = conn.unchecked_transaction()?;
+    let mut select = tx.prepare(&format!(
+        "select {} from current_price
+        where (?1 is null or state = ?1) and (?2 is null or fuel = ?2) and (?3 is null or station = ?3)
+        order by state, station, fuel",
+        CurrentPrice::COLUMNS
+    ))?;
+    let rows = select.query_map(
+        (state.map(|x| x as u8), fuel.map(|x| x as u8), station),
+        CurrentPrice::from_row,
+    )?;
+    Ok(Prices {
+        prices: rows.collect::<rusqlite::Result<_>>()?,
+        cursor: changes::latest(&tx)?,
+    })
+}
+
+#[derive(Serialize)]
+struct ListedStation {
+    state: State,
+    id: u32,
+    #[serde(flatten)]
+    station: changes::Station,
+}
+
+#[derive(Serialize)]
+struct Stations {
+    stations: Vec<ListedStation>,
+    /// The latest change included, pass as `since` to `/changes` to follow on
+    cursor: i64,
+}
+
+fn stations(conn: &Connection, query: &Query) -> Result<Stations> {
+    let state: Option<State> = query.get("state")?;
+
+    // read together so the cursor matches the stations
+    let tx = conn.unchecked_transaction()?;
+    let mut select = tx.prepare(
+        "select state, id, brand, latitude, longitude, location_flag, address, locality, postcode,
+            sa2, sa3, sa4, lga, poa
+        from station
+        where ?1 is null or state = ?1
+        order by state, id",
+    )?;
+    let rows = select.query_map([state.map(|x| x as u8)], |row| {
+        Ok(ListedStation {
+            state: row.get(0)?,
+            id: row.get(1)?,
+            station: changes::Station {
+                brand: row.get(2)?,
+                latitude: row.get(3)?,
+                longitude: row.get(4)?,
+                location_flag: row.get(5)?,
+                address: row.get(6)?,
+                locality: row.get(7)?,
+                postcode: row.get(8)?,
+                sa2: row.get(9)?,
+                sa3: row.get(10)?,
+                sa4: row.get(11)?,
+                lga: row.get(12)?,
+                poa: row.get(13)?,
+            },
+        })
+    })?;
+    Ok(Stations {
+        stations: rows.collect::<rusqlite::Result<_>>()?,
+        cursor: changes::latest(&tx)?,
+    })
+}
+
+#[derive(Serialize)]
+struct Station {
+    state: State,
+    id: u32,
+    brand: Option<String>,
+    latitude: f64,
+    longitude: f64,
+    updated_at: u64,
+    prices: Vec<CurrentPrice>,
+}
+
+fn station(conn: &Connection, state: State, id: u32) -> Result<Station> {
+    let mut station = conn
+        .query_row(
+            "select brand, latitude, longitude, updated_at from station where state = ? and id = ?",
+            (state as u8, id),
+            |row| {
+                Ok(Station {
+                    state,
+                    id,
+                    brand: row.get(0)?,
+                    latitude: row.get(1)?,
+                    longitude: row.get(2)?,
+                    updated_at: row.get(3)?,
+                    prices: Vec::new(),
+                })
+            },
+        )
+        .optional()?
+        .ok_or(Error::NotFound)?;
+
+    let mut select = conn.prepare(&format!(
+        "select {} from current_price where state = ? and station = ? order by fuel",
+        CurrentPrice::COLUMNS
+    ))?;
+    let rows = select.query_map((state as u8, id), CurrentPrice::from_row)?;
+    station.prices = rows.collect::<rusqlite::Result<_>>()?;
+    Ok(station)
+}
+
+#[derive(Serialize)]
+struct HistoricalPrice {
+    changed_at: u64,
+    price: Option<f64>,
+}
+
+fn history(
+    conn: &Connection,
+    state: State,
+    station: u32,
+    fuel: Fuel,
+    query: &Query,
+) -> Result<Vec<HistoricalPrice>> {
+    let from = query.time("from")?.unwrap_or(0);
+    let to = query.time("to")?.unwrap_or(i64::MAX as u64);
+
+    let mut select = conn.prepare(
+        "select changed_at, price from price_history
+        where state = ? and station = ? and fuel = ? and changed_at between ? and ?
+        order by changed_at",
+    )?;
+    let rows = select.query_map((state as u8, station, fuel as u8, from, to), |row| {
+        Ok(HistoricalPrice {
+            changed_at: row.get(0)?,
+            price: row.get(1)?,
+        })
+    })?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+fn cheapest(index: &StationIndex, query: &Query) -> Result<Vec<Nearby>> {
+    let fuel: Fuel = query.require("fuel")?;
+    let lat: f64 = query.require("lat")?;
+    let lon: f64 = query.require("lon")?;
+    let radius: f64 = query.get("radius")?.unwrap_or(10.0);
+    let detour_cost: f64 = query.get("detour_cost")?.unwrap_or(0.0);
+    let limit: usize = query.get("limit")?.unwrap_or(10);
+
+    Ok(index.nearest(Point::new(lon, lat), fuel, radius, detour_cost, limit))
+}
+
+fn cycles(conn: &Connection, query: &Query) -> Result<Vec<Cycle>> {
+    let state: Option<State> = query.get("state")?;
+    let fuel: Option<Fuel> = query.get("fuel")?;
+    let level: Option<String> = query.get("level")?;
+    Ok(cycles::load(conn, state, fuel, level.as_deref())?)
+}
+
+fn recommendation(
+    conn: &Connection,
+    index: &StationIndex,
+    query: &Query,
+) -> Result<Recommendation> {
+    let fuel: Fuel = query.require("fuel")?;
+    let lat: f64 = query.require("lat")?;
+    let lon: f64 = query.require("lon")?;
+    let radius: f64 = query.get("radius")?.unwrap_or(10.0);
+
+    recommend::recommend(conn, index, Point::new(lon, lat), fuel, radius)?.ok_or(Error::NotFound)
+}
+
+#[derive(Serialize)]
+struct ChangeFeed {
+    changes: Vec<Change>,
+    /// The last change's, or `since` if there were none
+    cursor: i64,
+}
+
+fn change_feed(conn: &Connection, query: &Query) -> Result<ChangeFeed> {
+    let since: i64 = query.get("since")?.unwrap_or(0);
+    let limit: usize = query.get("limit")?.unwrap_or(1000);
+    if limit > changes::MAX_LIMIT {
+        return Err(Error::BadRequest(format!(
+            "limit can be at most {}",
+            changes::MAX_LIMIT
+        )));
+    }
+
+    let changes = changes::since(conn, since, limit)?;
+    let cursor = changes.last().map_or(since, |x| x.cursor);
+    Ok(ChangeFeed { changes, cursor })
+}
+
+/// Most streams open at once, each has its own thread and database connection.
+pub const MAX_STREAMS: usize = 64;
+
+/// One of the [`MAX_STREAMS`], given back when dropped.
+struct StreamSlot(Arc<AtomicUsize>);
+
+impl StreamSlot {
+    fn take(streams: &Arc<AtomicUsize>) -> Option<Self> {
+        streams
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
+                (x < MAX_STREAMS).then_some(x + 1)
+            })
+            .ok()?;
+        Some(Self(streams.clone()))
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// how often streams check for new changes
+const STREAM_POLL: Duration = Duration::from_secs(2);
+
+// comments sent while nothing changes, so proxies keep the connection open
+// and a client that went away is noticed
+const STREAM_KEEPALIVE: Duration = Duration::from_secs(30);
+
+fn stream_filter(conn: &Connection, request: &Request, query: &Query) -> Result<(i64, Filter)> {
+    let bbox = match query.0.get("bbox") {
+        Some(x) => {
+            let values = x.split(',').map(parse).collect::<Result<Vec<f64>>>()?;
+            let bbox: [f64; 4] = values
+                .try_into()
+                .map_err(|_| Error::BadRequest("bbox needs 4 values".into()))?;
+            Some(bbox)
+        }
+        None => None,
+    };
+    let last_event = request
+        .headers()
+        .iter()
+        .find(|x| x.field.equiv("Last-Event-ID"))
+        .map(|x| parse(x.value.as_str()))
+        .transpose()?;
+    let since = match last_event.or(query.get("since")?) {
+        Some(x) => x,
+        None => changes::latest(conn)?,
+    };
+    let filter = Filter {
+        state: query.get("state")?,
+        fuel: query.get("fuel")?,
+        bbox,
+    };
+    Ok((since, filter))
+}
+
+/// Sends each price change matching `filter` after cursor `since` as an event
+/// with the cursor as its id, until the client goes away.
+fn stream(request: Request, mut since: i64, filter: Filter) -> anyhow::Result<()> {
+    let conn = db::open()?;
+    // tiny_http buffers streamed bodies, so the response is written straight to
+    // the connection. The body runs until it's closed
+    let mut writer = request.into_writer();
+    writer.write_all(
+        b"HTTP/1.1 200 OK\r\n\
+        Content-Type: text/event-stream\r\n\
+        Cache-Control: no-cache\r\n\
+        Connection: close\r\n\
+        \r\n",
+    )?;
+    writer.write_all(b"retry: 5000\n\n")?;
+    writer.flush()?;
+
+    let mut quiet = Instant::now();
+    loop {
+        let latest = changes::latest(&conn)?;
+        if latest > since {
+            for x in changes::prices(&conn, since, latest, &filter)? {
+                write!(writer, "id: {}\nevent: price\ndata: ", x.cursor)?;
+                serde_json::to_writer(&mut writer, &x)?;
+                writer.write_all(b"\n\n")?;
+                quiet = Instant::now();
+            }
+            since = latest;
+        }
+        if quiet.elapsed() >= STREAM_KEEPALIVE {
+            writer.write_all(b": keepalive\n\n")?;
+            quiet = Instant::now();
+        }
+        writer.flush()?;
+        thread::sleep(STREAM_POLL);
+    }
+}
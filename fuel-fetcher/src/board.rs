@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::{Fuel, State};
+
+#[derive(Serialize)]
+pub struct BoardPrice {
+    pub state: State,
+    pub station: u32,
+    pub fuel: Fuel,
+    // cents per liter, none if the fuel was unavailable
+    pub price: Option<f64>,
+    pub changed_at: u64,
+}
+
+// (state, station, fuel) to (price, changed_at)
+type Board = BTreeMap<(u8, u32, u8), (Option<f64>, u64)>;
+
+/// Every station's price at `time` (unix seconds), reconstructed from
+/// `price_history`. Starts from the latest snapshot before `time` if there is
+/// one, so only the changes since need to be read.
+pub fn at(
+    conn: &Connection,
+    time: u64,
+    state: Option<State>,
+    fuel: Option<Fuel>,
+) -> Result<Vec<BoardPrice>> {
+    let board = load(conn, time, state, fuel)?;
+    let mut prices = Vec::new();
+    for ((state, station, fuel), (price, changed_at)) in board {
+        prices.push(BoardPrice {
+            state: state.try_into().expect("valid state"),
+            station,
+            fuel: fuel.try_into().expect("valid fuel"),
+            price,
+            changed_at,
+        });
+    }
+    Ok(prices)
+}
+
+fn load(conn: &Connection, time: u64, state: Option<State>, fuel: Option<Fuel>) -> Result<Board> {
+    let state = state.map(|x| x as u8);
+    let fuel = fuel.map(|x| x as u8);
+    let mut board = Board::new();
+
+    let snapshot: Option<u64> = conn.query_row(
+        "select max(taken_at) from price_snapshot where taken_at <= ?",
+        [time],
+        |row| row.get(0),
+    )?;
+
+    let Some(snapshot) = snapshot else {
+        // no snapshot to start from, the latest change of each is found with the index
+        let mut select = conn.prepare(
+            "select state, station, fuel, price, max(changed_at) from price_history
+            where changed_at <= ?1 and (?2 is null or state = ?2) and (?3 is null or fuel = ?3)
+            group by state, station, fuel",
+        )?;
+        let mut rows = select.query((time, state, fuel))?;
+        while let Some(row) = rows.next()? {
+            board.insert(
+                (row.get(0)?, row.get(1)?, row.get(2)?),
+                (row.get(3)?, row.get(4)?),
+            );
+        }
+        return Ok(board);
+    };
+
+    let mut select = conn.prepare(
+        "select state, station, fuel, price, changed_at from price_snapshot
+        where taken_at = ?1 and (?2 is null or state = ?2) and (?3 is null or fuel = ?3)",
+    )?;
+    let mut rows = select.query((snapshot, state, fuel))?;
+    while let Some(row) = rows.next()? {
+        board.insert(
+            (row.get(0)?, row.get(1)?, row.get(2)?),
+            (row.get(3)?, row.get(4)?),
+        );
+    }
+
+    // one state at a time so the (state, changed_at) index is used
+    let mut select = conn.prepare(
+        "select state, station, fuel, price, changed_at from price_history
+        where state = ?1 and changed_at > ?2 and changed_at <= ?3 and (?4 is null or fuel = ?4)
+        order by changed_at, rowid",
+    )?;
+    for x in State::all() {
+        if state.is_some_and(|state| state != x as u8) {
+            continue;
+        }
+        let mut rows = select.query((x as u8, snapshot, time, fuel))?;
+        while let Some(row) = rows.next()? {
+            board.insert(
+                (row.get(0)?, row.get(1)?, row.get(2)?),
+                (row.get(3)?, row.get(4)?),
+            );
+        }
+    }
+    Ok(board)
+}
+
+/// Materialises the board at `time` into `price_snapshot`, returning how many
+/// prices it has.
+pub fn snapshot(conn: &Connection, time: u64) -> Result<usize> {
+    let board = load(conn, time, None, None)?;
+    let tx = conn.unchecked_transaction()?;
+    {
+        tx.execute("delete from price_snapshot where taken_at = ?", [time])?;
+        let mut insert = tx.prepare(
+            "insert into price_snapshot (taken_at, state, station, fuel, changed_at, price)
+            values (?, ?, ?, ?, ?, ?)",
+        )?;
+        for ((state, station, fuel), (price, changed_at)) in &board {
+            insert.execute((time, state, station, fuel, changed_at, price))?;
+        }
+    }
+    tx.commit()?;
+    Ok(board.len())
+}
+
+/// Takes a snapshot at every multiple of `every` seconds (so daily ones are at
+/// midnight UTC) after the latest one, or the start of the history, up to
+/// now. Each is built from the one before. Returns the times snapshotted.
+pub fn snapshots(conn: &Connection, every: u64) -> Result<Vec<u64>> {
+    let latest: Option<u64> = conn.query_row(
+        "select coalesce((select max(taken_at) from price_snapshot), (select min(changed_at) from price_history))",
+        [],
+        |row| row.get(0),
+    )?;
+    let Some(latest) = latest else {
+        return Ok(Vec::new());
+    };
+
+    let mut taken = Vec::new();
+    let mut time = (latest / every + 1) * every;
+    while time <= crate::now() {
+        snapshot(conn, time)?;
+        taken.push(time);
+        time += every;
+    }
+    Ok(taken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    fn board(
+        conn: &Connection,
+        time: u64,
+        state: Option<State>,
+    ) -> Vec<(u8, u32, u8, Option<f64>, u64)> {
+        at(conn, time, state, None)
+            .unwrap()
+            .into_iter()
+            .map(|x| {
+                (
+                    x.state as u8,
+                    x.station,
+                    x.fuel as u8,
+                    x.price,
+                    x.changed_at,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn snapshot_gives_the_same_board() {
+        let conn = db::open_in_memory().unwrap();
+        let (u91, diesel) = (Fuel::Unleaded91 as u8, Fuel::Diesel as u8);
+        let changes = [
+            (State::NSW, 1, u91, 100, Some(180.0)),
+            (State::NSW, 1, diesel, 100, Some(200.0)),
+            (State::NSW, 2, u91, 150, Some(185.0)),
+            (State::QLD, 1, u91, 120, Some(175.0)),
+            // after the snapshot: a change, one running out and a new price
+            (State::NSW, 1, u91, 250, Some(170.0)),
+            (State::NSW, 2, u91, 260, None),
+            (State::QLD, 2, u91, 270, Some(179.0)),
+            // after the board's time
+            (State::NSW, 1, u91, 400, Some(160.0)),
+        ];
+        for (state, station, fuel, changed_at, price) in changes {
+            conn.execute(
+                "insert into price_history (state, station, fuel, changed_at, price)
+                values (?, ?, ?, ?, ?)",
+                (state as u8, station, fuel, changed_at, price),
+            )
+            .unwrap();
+        }
+
+        let before: Vec<_> = [200, 300]
+            .map(|time| {
+                (
+                    board(&conn, time, None),
+                    board(&conn, time, Some(State::NSW)),
+                )
+            })
+            .into();
+        assert_eq!(
+            before[1].1,
+            [
+                (State::NSW as u8, 1, diesel, Some(200.0), 100),
+                (State::NSW as u8, 1, u91, Some(170.0), 250),
+                (State::NSW as u8, 2, u91, None, 260),
+            ]
+        );
+
+        snapshot(&conn, 200).unwrap();
+        let after: Vec<_> = [200, 300]
+            .map(|time| {
+                (
+                    board(&conn, time, None),
+                    board(&conn, time, Some(State::NSW)),
+                )
+            })
+            .into();
+        assert_eq!(before, after);
+    }
+}
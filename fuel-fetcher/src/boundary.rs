@@ -0,0 +1,271 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use geo::{BoundingRect, Geometry, Intersects, MultiPolygon, Point, Polygon};
+use geojson::GeoJson;
+use rstar::{
+    primitives::{GeomWithData, Rectangle},
+    RTree,
+};
+use rusqlite::Connection;
+use serde::Deserialize;
+use shapefile::{dbase::FieldValue, Shape};
+
+/// Kinds of ABS boundary a station can be assigned to, each stored in its own
+/// column of `station`.
+#[derive(Debug, Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Sa2,
+    Sa3,
+    Sa4,
+    Lga,
+    /// Postal areas
+    Poa,
+}
+
+impl Level {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Sa2 => "sa2",
+            Self::Sa3 => "sa3",
+            Self::Sa4 => "sa4",
+            Self::Lga => "lga",
+            Self::Poa => "poa",
+        }
+    }
+
+    pub const fn all() -> [Level; 5] {
+        use Level::*;
+        [Sa2, Sa3, Sa4, Lga, Poa]
+    }
+}
+
+struct Boundary {
+    code: String,
+    name: Option<String>,
+    geometry: MultiPolygon,
+}
+
+/// Replaces the `level` boundaries with those in a GeoJSON file or shapefile,
+/// clearing every station's so [`assign`] redoes them. Returns how many
+/// boundaries were imported.
+///
+/// Coordinates must be longitude and latitude (GDA2020 or WGS 84, like the ABS
+/// downloads). The code and name are read from `code_field` and `name_field`,
+/// or by default the first property like `SA2_CODE21` and `SA2_NAME21`.
+pub fn import(
+    conn: &Connection,
+    level: Level,
+    path: &Path,
+    code_field: Option<&str>,
+    name_field: Option<&str>,
+) -> Result<usize> {
+    let shp = path
+        .extension()
+        .is_some_and(|x| x.eq_ignore_ascii_case("shp"));
+    let features = if shp {
+        read_shapefile(path)?
+    } else {
+        read_geojson(path)?
+    };
+
+    let prefix = level.as_str().to_ascii_uppercase();
+    let field = |properties: &HashMap<String, String>, field: Option<&str>, kind: &str| {
+        let default = format!("{prefix}_{kind}");
+        properties
+            .iter()
+            .find(|(k, _)| match field {
+                Some(x) => k.eq_ignore_ascii_case(x),
+                None => k.to_ascii_uppercase().starts_with(&default),
+            })
+            .map(|(_, v)| v.clone())
+    };
+
+    let mut boundaries = Vec::new();
+    for (i, (properties, geometry)) in features.into_iter().enumerate() {
+        // some boundaries like "migratory" SA2s have no geometry
+        let Some(geometry) = geometry else {
+            continue;
+        };
+        let code = field(&properties, code_field, "CODE").with_context(|| {
+            let mut keys: Vec<_> = properties.keys().collect();
+            keys.sort();
+            format!("feature {i} has no code, properties are {keys:?}")
+        })?;
+        boundaries.push(Boundary {
+            code,
+            name: field(&properties, name_field, "NAME"),
+            geometry,
+        });
+    }
+    if boundaries.is_empty() {
+        bail!("{path:?} has no polygons");
+    }
+
+    let tx = conn.unchecked_transaction()?;
+    {
+        tx.execute("delete from boundary where level = ?", [level.as_str()])?;
+        let mut insert = tx.prepare(
+            "insert or replace into boundary (level, code, name, geometry) values (?, ?, ?, ?)",
+        )?;
+        for x in &boundaries {
+            let geometry = geojson::Geometry::from(&x.geometry);
+            insert.execute((level.as_str(), &x.code, &x.name, geometry.to_string()))?;
+        }
+        tx.execute(&format!("update station set {} = null", level.as_str()), [])?;
+    }
+    tx.commit()?;
+
+    Ok(boundaries.len())
+}
+
+/// Assigns stations without a `level` boundary to the one they're inside,
+/// returning how many were assigned. Does nothing if the level hasn't been
+/// imported.
+pub fn assign(conn: &Connection, level: Level) -> Result<usize> {
+    let column = level.as_str();
+    let mut stations = Vec::new();
+    {
+        let mut select = conn.prepare(&format!(
+            "select state, id, latitude, longitude from station
+            where {column} is null and not (latitude = 0 and longitude = 0)"
+        ))?;
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            let key: (u8, u32) = (row.get(0)?, row.get(1)?);
+            stations.push((key, Point::new(row.get(3)?, row.get(2)?)));
+        }
+    }
+    if stations.is_empty() {
+        return Ok(0);
+    }
+
+    let mut boundaries = Vec::new();
+    {
+        let mut select = conn.prepare("select code, geometry from boundary where level = ?")?;
+        let mut rows = select.query([column])?;
+        while let Some(row) = rows.next()? {
+            let code: String = row.get(0)?;
+            let geometry: String = row.get(1)?;
+            let geometry: geojson::Geometry = geometry.parse()?;
+            let Some(geometry) = multi_polygon(Geometry::try_from(geometry)?) else {
+                bail!("{column} boundary {code} isn't a polygon");
+            };
+            boundaries.push((code, geometry));
+        }
+    }
+    if boundaries.is_empty() {
+        return Ok(0);
+    }
+
+    // boxes around each boundary, the value is an index into `boundaries`
+    let tree = RTree::bulk_load(
+        boundaries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (_, x))| {
+                let rect = x.bounding_rect()?;
+                let (min, max) = (rect.min(), rect.max());
+                Some(GeomWithData::new(
+                    Rectangle::from_corners([min.x, min.y], [max.x, max.y]),
+                    i,
+                ))
+            })
+            .collect(),
+    );
+
+    let mut assigned = 0;
+    let tx = conn.unchecked_transaction()?;
+    {
+        let mut update = tx.prepare(&format!(
+            "update station set {column} = ? where state = ? and id = ?"
+        ))?;
+        for ((state, id), point) in stations {
+            let found = tree
+                .locate_all_at_point(&[point.x(), point.y()])
+                .map(|x| &boundaries[x.data])
+                .find(|(_, x)| x.intersects(&point));
+            if let Some((code, _)) = found {
+                update.execute((code, state, id))?;
+                assigned += 1;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(assigned)
+}
+
+type Feature = (HashMap<String, String>, Option<MultiPolygon>);
+
+fn read_geojson(path: &Path) -> Result<Vec<Feature>> {
+    let geojson: GeoJson = fs::read_to_string(path)?.parse()?;
+    let GeoJson::FeatureCollection(collection) = geojson else {
+        bail!("{path:?} isn't a FeatureCollection");
+    };
+
+    let mut features = Vec::new();
+    for feature in collection.features {
+        let properties = feature
+            .properties
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(k, v)| {
+                let v = match v {
+                    serde_json::Value::String(x) => x,
+                    serde_json::Value::Number(x) => x.to_string(),
+                    _ => return None,
+                };
+                Some((k, v))
+            })
+            .collect();
+        let geometry = match feature.geometry {
+            Some(x) => multi_polygon(Geometry::try_from(x)?),
+            None => None,
+        };
+        features.push((properties, geometry));
+    }
+    Ok(features)
+}
+
+fn read_shapefile(path: &Path) -> Result<Vec<Feature>> {
+    let mut reader = shapefile::Reader::from_path(path)?;
+    let mut features = Vec::new();
+    for x in reader.iter_shapes_and_records() {
+        let (shape, record) = x?;
+        let properties = record
+            .into_iter()
+            .filter_map(|(k, v)| {
+                let v = match v {
+                    FieldValue::Character(Some(x)) => x.trim().to_string(),
+                    FieldValue::Numeric(Some(x)) => x.to_string(),
+                    FieldValue::Integer(x) => x.to_string(),
+                    _ => return None,
+                };
+                Some((k, v))
+            })
+            .collect();
+        let geometry = match shape {
+            Shape::Polygon(x) => Some(MultiPolygon::try_from(x)?),
+            Shape::NullShape => None,
+            x => bail!("{path:?} has a {} shape, expected polygons", x.shapetype()),
+        };
+        features.push((properties, geometry));
+    }
+    Ok(features)
+}
+
+/// Polygons in `geometry`, if there are any.
+fn multi_polygon(geometry: Geometry) -> Option<MultiPolygon> {
+    fn polygons(geometry: Geometry) -> Vec<Polygon> {
+        match geometry {
+            Geometry::Polygon(x) => vec![x],
+            Geometry::MultiPolygon(x) => x.0,
+            Geometry::GeometryCollection(x) => x.into_iter().flat_map(polygons).collect(),
+            _ => Vec::new(),
+        }
+    }
+    let polygons = polygons(geometry);
+    (!polygons.is_empty()).then(|| MultiPolygon::new(polygons))
+}
@@ -0,0 +1,167 @@
+use anyhow::Result;
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::{Fuel, State};
+
+/// Most changes returned at once, to keep responses a reasonable size.
+pub const MAX_LIMIT: usize = 10_000;
+
+#[derive(Serialize)]
+pub struct Change {
+    /// Pass as `since` to get the changes after this one
+    pub cursor: i64,
+    pub state: State,
+    pub station: u32,
+    pub changed_at: u64,
+    #[serde(flatten)]
+    pub data: Data,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Data {
+    Price {
+        fuel: Fuel,
+        // cents per liter, none if the fuel became unavailable
+        price: Option<f64>,
+    },
+    /// The station as it is now, not as it was at the change
+    Station(Box<Station>),
+}
+
+#[derive(Serialize)]
+pub struct Station {
+    pub brand: Option<String>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub location_flag: Option<String>,
+    pub address: Option<String>,
+    pub locality: Option<String>,
+    pub postcode: Option<String>,
+    pub sa2: Option<String>,
+    pub sa3: Option<String>,
+    pub sa4: Option<String>,
+    pub lga: Option<String>,
+    pub poa: Option<String>,
+}
+
+/// Up to `limit` changes after cursor `since`, oldest first. Start from 0, or
+/// from the cursor of a snapshot of the tables, see [`crate::api::serve`].
+pub fn since(conn: &Connection, since: i64, limit: usize) -> Result<Vec<Change>> {
+    let mut select = conn.prepare(
+        "select change_log.id, kind, change_log.state, station, changed_at, fuel, price,
+            brand, latitude, longitude, location_flag, address, locality, postcode, sa2, sa3, sa4, lga, poa
+        from change_log
+        left join station on kind = 'station' and station.state = change_log.state and station.id = station
+        where change_log.id > ?
+        order by change_log.id
+        limit ?",
+    )?;
+    let rows = select.query_map((since, limit.min(MAX_LIMIT)), |row| {
+        let kind: String = row.get(1)?;
+        Ok(Change {
+            cursor: row.get(0)?,
+            state: row.get(2)?,
+            station: row.get(3)?,
+            changed_at: row.get(4)?,
+            data: if kind == "price" {
+                Data::Price {
+                    fuel: row.get(5)?,
+                    price: row.get(6)?,
+                }
+            } else {
+                Data::Station(Box::new(Station {
+                    brand: row.get(7)?,
+                    latitude: row.get(8)?,
+                    longitude: row.get(9)?,
+                    location_flag: row.get(10)?,
+                    address: row.get(11)?,
+                    locality: row.get(12)?,
+                    postcode: row.get(13)?,
+                    sa2: row.get(14)?,
+                    sa3: row.get(15)?,
+                    sa4: row.get(16)?,
+                    lga: row.get(17)?,
+                    poa: row.get(18)?,
+                }))
+            },
+        })
+    })?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+/// The cursor of the latest change, 0 if there are none.
+pub fn latest(conn: &Connection) -> Result<i64> {
+    Ok(
+        conn.query_row("select coalesce(max(id), 0) from change_log", [], |row| {
+            row.get(0)
+        })?,
+    )
+}
+
+#[derive(Serialize)]
+pub struct PriceChange {
+    pub cursor: i64,
+    pub state: State,
+    pub station: u32,
+    pub fuel: Fuel,
+    // cents per liter, none if the fuel became unavailable
+    pub price: Option<f64>,
+    pub changed_at: u64,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+#[derive(Default)]
+pub struct Filter {
+    pub state: Option<State>,
+    pub fuel: Option<Fuel>,
+    /// Stations inside `[min_lon, min_lat, max_lon, max_lat]`
+    pub bbox: Option<[f64; 4]>,
+}
+
+/// The price changes after cursor `since` up to and including `until` that
+/// match `filter`, oldest first.
+pub fn prices(
+    conn: &Connection,
+    since: i64,
+    until: i64,
+    filter: &Filter,
+) -> Result<Vec<PriceChange>> {
+    let mut select = conn.prepare(
+        "select change_log.id, change_log.state, station, fuel, price, changed_at, latitude, longitude
+        from change_log
+        left join station on station.state = change_log.state and station.id = station
+        where kind = 'price' and change_log.id > ?1 and change_log.id <= ?2
+            and (?3 is null or change_log.state = ?3) and (?4 is null or fuel = ?4)
+            and (?5 is null or longitude between ?5 and ?7 and latitude between ?6 and ?8)
+        order by change_log.id",
+    )?;
+    let bbox = filter.bbox.map(|x| x.map(Some)).unwrap_or_default();
+    let rows = select.query_map(
+        (
+            since,
+            until,
+            filter.state.map(|x| x as u8),
+            filter.fuel.map(|x| x as u8),
+            bbox[0],
+            bbox[1],
+            bbox[2],
+            bbox[3],
+        ),
+        |row| {
+            Ok(PriceChange {
+                cursor: row.get(0)?,
+                state: row.get(1)?,
+                station: row.get(2)?,
+                fuel: row.get(3)?,
+                price: row.get(4)?,
+                changed_at: row.get(5)?,
+                latitude: row.get(6)?,
+                longitude: row.get(7)?,
+            })
+        },
+    )?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
@@ -0,0 +1,163 @@
+use geo::{polygon, Contains, Distance, Euclidean, Point, Polygon};
+
+use crate::{State, Station};
+
+// degrees, so stations just over a border of the rough outlines below aren't flagged
+const TOLERANCE: f64 = 0.05;
+
+/// Something wrong with a station's location, stored in `station.location_flag`.
+#[derive(Debug, Clone, Copy)]
+pub enum Problem {
+    /// Reported at (0, 0), usually a missing location
+    Zero,
+    /// Latitude and longitude were the wrong way around, already fixed
+    Swapped,
+    /// Inside a different state
+    WrongState(State),
+    /// Not inside any state we cover
+    Outside,
+}
+
+impl Problem {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Zero => "zero",
+            Self::Swapped => "swapped",
+            Self::WrongState(_) => "wrong_state",
+            Self::Outside => "outside",
+        }
+    }
+}
+
+/// Checks a station's location, swapping latitude and longitude if that puts
+/// it where it should be.
+pub fn check(station: &mut Station) -> Option<Problem> {
+    let point = station.point;
+    if point.x() == 0.0 && point.y() == 0.0 {
+        return Some(Problem::Zero);
+    }
+    if within(station.state, point) {
+        return None;
+    }
+
+    let swapped = Point::new(point.y(), point.x());
+    if within(station.state, swapped) {
+        station.point = swapped;
+        return Some(Problem::Swapped);
+    }
+
+    match State::all().into_iter().find(|x| within(*x, point)) {
+        Some(x) => Some(Problem::WrongState(x)),
+        None => Some(Problem::Outside),
+    }
+}
+
+fn within(state: State, point: Point) -> bool {
+    let bounds = bounds(state);
+    bounds.contains(&point) || Euclidean.distance(&point, &bounds) < TOLERANCE
+}
+
+/// Rough outlines of each state as (longitude, latitude), good enough to catch
+/// a station that's in the wrong place but not for anything near a border.
+fn bounds(state: State) -> Polygon {
+    match state {
+        State::NSW => polygon![
+            (x: 141.0, y: -29.0),
+            (x: 149.0, y: -29.0),
+            (x: 150.5, y: -28.6),
+            (x: 152.0, y: -28.3),
+            (x: 153.55, y: -28.16),
+            (x: 154.0, y: -28.16),
+            (x: 154.0, y: -37.6),
+            (x: 149.98, y: -37.5),
+            (x: 148.2, y: -36.8),
+            (x: 147.0, y: -36.0),
+            (x: 144.5, y: -35.8),
+            (x: 143.0, y: -34.8),
+            (x: 141.0, y: -34.0),
+        ],
+        State::NT => polygon![
+            (x: 129.0, y: -26.0),
+            (x: 138.0, y: -26.0),
+            (x: 138.0, y: -10.5),
+            (x: 129.0, y: -10.5),
+        ],
+        State::QLD => polygon![
+            (x: 138.0, y: -10.0),
+            (x: 138.0, y: -26.0),
+            (x: 141.0, y: -26.0),
+            (x: 141.0, y: -29.0),
+            (x: 149.0, y: -29.0),
+            (x: 150.5, y: -28.6),
+            (x: 152.0, y: -28.3),
+            (x: 153.55, y: -28.16),
+            (x: 154.0, y: -28.16),
+            (x: 154.0, y: -10.0),
+        ],
+        State::SA => polygon![
+            (x: 129.0, y: -26.0),
+            (x: 141.0, y: -26.0),
+            (x: 141.0, y: -38.5),
+            (x: 129.0, y: -38.5),
+        ],
+        State::TAS => polygon![
+            (x: 143.5, y: -39.2),
+            (x: 148.7, y: -39.2),
+            (x: 148.7, y: -44.0),
+            (x: 143.5, y: -44.0),
+        ],
+        State::WA => polygon![
+            (x: 112.5, y: -35.5),
+            (x: 129.0, y: -35.5),
+            (x: 129.0, y: -13.5),
+            (x: 112.5, y: -13.5),
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn station(state: State, longitude: f64, latitude: f64) -> Station {
+        Station {
+            state,
+            id: 1,
+            brand: None,
+            point: Point::new(longitude, latitude),
+        }
+    }
+
+    #[test]
+    fn valid() {
+        let mut sydney = station(State::NSW, 151.21, -33.87);
+        assert!(check(&mut sydney).is_none());
+        assert_eq!(sydney.point.x_y(), (151.21, -33.87));
+    }
+
+    #[test]
+    fn swapped() {
+        let mut sydney = station(State::NSW, -33.87, 151.21);
+        assert!(matches!(check(&mut sydney), Some(Problem::Swapped)));
+        assert_eq!(sydney.point.x_y(), (151.21, -33.87));
+    }
+
+    #[test]
+    fn outside_australia() {
+        let mut auckland = station(State::NSW, 174.76, -36.85);
+        assert!(matches!(check(&mut auckland), Some(Problem::Outside)));
+        assert_eq!(auckland.point.x_y(), (174.76, -36.85));
+
+        let mut zero = station(State::NSW, 0.0, 0.0);
+        assert!(matches!(check(&mut zero), Some(Problem::Zero)));
+    }
+
+    #[test]
+    fn wrong_state() {
+        let mut perth = station(State::NSW, 115.86, -31.95);
+        assert!(matches!(
+            check(&mut perth),
+            Some(Problem::WrongState(State::WA))
+        ));
+    }
+}
@@ -0,0 +1,457 @@
+use anyhow::Result;
+use chrono::NaiveDate;
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::{Fuel, State};
+
+/// Cents per liter the median has to move from a low or high before it counts
+/// as a turning point, small enough for a cycle but bigger than daily noise.
+pub const THRESHOLD: f64 = 5.0;
+
+// cycles used for the length and amplitude, older ones may have been different
+const RECENT: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Trough,
+    Peak,
+}
+
+impl Kind {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Trough => "trough",
+            Self::Peak => "peak",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    /// Since a trough, usually a sharp hike
+    Rising,
+    /// Since a peak, usually slow discounting
+    Falling,
+}
+
+impl Phase {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Rising => "rising",
+            Self::Falling => "falling",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Turn {
+    pub date: NaiveDate,
+    pub kind: Kind,
+    // cents per liter
+    pub price: f64,
+}
+
+#[derive(Serialize)]
+pub struct Cycle {
+    pub state: State,
+    pub level: String,
+    pub region: String,
+    pub fuel: Fuel,
+    pub updated_at: u64,
+    pub cycles: usize,
+    // days from trough to trough
+    pub length: Option<f64>,
+    // cents per liter from trough to peak
+    pub amplitude: Option<f64>,
+    pub phase: Option<Phase>,
+    pub last_trough: Option<NaiveDate>,
+    pub last_peak: Option<NaiveDate>,
+}
+
+/// Finds troughs and peaks in a daily series: a low is a trough once prices
+/// have risen `threshold` above it, and a high is a peak once they've fallen
+/// `threshold` below it. Also returns which way prices are going since the
+/// last one.
+pub fn turning_points(series: &[(NaiveDate, f64)], threshold: f64) -> (Vec<Turn>, Option<Phase>) {
+    let mut turns = Vec::new();
+    let Some(&first) = series.first() else {
+        return (turns, None);
+    };
+    let mut phase = None;
+    // lowest and highest since the last turning point
+    let (mut low, mut high) = (first, first);
+
+    for &(date, price) in &series[1..] {
+        if price < low.1 {
+            low = (date, price);
+        }
+        if price > high.1 {
+            high = (date, price);
+        }
+        match phase {
+            None if price >= low.1 + threshold => {
+                turns.push(turn(low, Kind::Trough));
+                phase = Some(Phase::Rising);
+                (low, high) = ((date, price), (date, price));
+            }
+            None if price <= high.1 - threshold => {
+                turns.push(turn(high, Kind::Peak));
+                phase = Some(Phase::Falling);
+                (low, high) = ((date, price), (date, price));
+            }
+            Some(Phase::Rising) if price <= high.1 - threshold => {
+                turns.push(turn(high, Kind::Peak));
+                phase = Some(Phase::Falling);
+                (low, high) = ((date, price), (date, price));
+            }
+            Some(Phase::Falling) if price >= low.1 + threshold => {
+                turns.push(turn(low, Kind::Trough));
+                phase = Some(Phase::Rising);
+                (low, high) = ((date, price), (date, price));
+            }
+            _ => {}
+        }
+    }
+    (turns, phase)
+}
+
+fn turn((date, price): (NaiveDate, f64), kind: Kind) -> Turn {
+    Turn { date, kind, price }
+}
+
+// (state, level, region, fuel)
+type Group = (State, String, String, Fuel);
+
+/// Detects cycles in every region and fuel in `price_daily`, along with their
+/// turning points.
+pub fn detect(conn: &Connection, threshold: f64) -> Result<Vec<(Cycle, Vec<Turn>)>> {
+    // each region and fuel's daily medians
+    let mut groups: Vec<(Group, Vec<(NaiveDate, f64)>)> = Vec::new();
+    {
+        let mut select = conn.prepare(
+            "select state, level, region, fuel, date, median from price_daily
+            order by state, level, region, fuel, date",
+        )?;
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            let state: State = row.get(0)?;
+            let level: String = row.get(1)?;
+            let region: String = row.get(2)?;
+            let fuel: Fuel = row.get(3)?;
+            let day = (row.get(4)?, row.get(5)?);
+            match groups.last_mut() {
+                Some((key, series))
+                    if key.0 as u8 == state as u8
+                        && key.1 == level
+                        && key.2 == region
+                        && key.3 as u8 == fuel as u8 =>
+                {
+                    series.push(day)
+                }
+                _ => groups.push(((state, level, region, fuel), vec![day])),
+            }
+        }
+    }
+
+    let now = crate::now();
+    Ok(groups
+        .into_iter()
+        .map(|((state, level, region, fuel), series)| {
+            let (turns, phase) = turning_points(&series, threshold);
+            let summary = summarise(&turns, phase);
+            let cycle = Cycle {
+                state,
+                level,
+                region,
+                fuel,
+                updated_at: now,
+                cycles: summary.cycles,
+                length: summary.length,
+                amplitude: summary.amplitude,
+                phase: summary.phase,
+                last_trough: summary.last_trough,
+                last_peak: summary.last_peak,
+            };
+            (cycle, turns)
+        })
+        .collect())
+}
+
+/// Detects cycles with [`THRESHOLD`], replacing what's in `price_cycle` and
+/// `price_cycle_point`. Returns how many were analysed.
+pub fn update(conn: &Connection) -> Result<usize> {
+    let detected = detect(conn, THRESHOLD)?;
+    let tx = conn.unchecked_transaction()?;
+    {
+        tx.execute("delete from price_cycle", [])?;
+        tx.execute("delete from price_cycle_point", [])?;
+        let mut insert_point = tx.prepare(
+            "insert into price_cycle_point (state, level, region, fuel, date, kind, price)
+            values (?, ?, ?, ?, ?, ?, ?)",
+        )?;
+        let mut insert = tx.prepare(
+            "insert into price_cycle (state, level, region, fuel, updated_at, cycles, length, amplitude, phase, last_trough, last_peak)
+            values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )?;
+
+        for (cycle, turns) in &detected {
+            for x in turns {
+                insert_point.execute((
+                    cycle.state as u8,
+                    &cycle.level,
+                    &cycle.region,
+                    cycle.fuel as u8,
+                    x.date,
+                    x.kind.as_str(),
+                    x.price,
+                ))?;
+            }
+            insert.execute((
+                cycle.state as u8,
+                &cycle.level,
+                &cycle.region,
+                cycle.fuel as u8,
+                cycle.updated_at,
+                cycle.cycles,
+                cycle.length,
+                cycle.amplitude,
+                cycle.phase.map(|x| x.as_str()),
+                cycle.last_trough,
+                cycle.last_peak,
+            ))?;
+        }
+    }
+    tx.commit()?;
+    Ok(detected.len())
+}
+
+struct Summary {
+    cycles: usize,
+    length: Option<f64>,
+    amplitude: Option<f64>,
+    phase: Option<Phase>,
+    last_trough: Option<NaiveDate>,
+    last_peak: Option<NaiveDate>,
+}
+
+fn summarise(turns: &[Turn], phase: Option<Phase>) -> Summary {
+    let troughs: Vec<&Turn> = turns.iter().filter(|x| x.kind == Kind::Trough).collect();
+    let lengths: Vec<f64> = troughs
+        .windows(2)
+        .map(|x| (x[1].date - x[0].date).num_days() as f64)
+        .collect();
+    let amplitudes: Vec<f64> = turns
+        .windows(2)
+        .filter(|x| x[0].kind == Kind::Trough)
+        .map(|x| x[1].price - x[0].price)
+        .collect();
+    Summary {
+        cycles: lengths.len(),
+        length: median(&lengths[lengths.len().saturating_sub(RECENT)..]),
+        amplitude: median(&amplitudes[amplitudes.len().saturating_sub(RECENT)..]),
+        phase,
+        last_trough: troughs.last().map(|x| x.date),
+        last_peak: turns
+            .iter()
+            .rev()
+            .find(|x| x.kind == Kind::Peak)
+            .map(|x| x.date),
+    }
+}
+
+fn median(values: &[f64]) -> Option<f64> {
+    let mut values = values.to_vec();
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    match values.len() {
+        0 => None,
+        x if x % 2 == 0 => Some((values[mid - 1] + values[mid]) / 2.0),
+        _ => Some(values[mid]),
+    }
+}
+
+/// The stored cycles, optionally of one state, fuel or level.
+pub fn load(
+    conn: &Connection,
+    state: Option<State>,
+    fuel: Option<Fuel>,
+    level: Option<&str>,
+) -> Result<Vec<Cycle>> {
+    let mut select = conn.prepare(
+        "select state, level, region, fuel, updated_at, cycles, length, amplitude, phase, last_trough, last_peak
+        from price_cycle
+        where (?1 is null or state = ?1) and (?2 is null or fuel = ?2) and (?3 is null or level = ?3)
+        order by state, level, region, fuel",
+    )?;
+    let rows = select.query_map(
+        (state.map(|x| x as u8), fuel.map(|x| x as u8), level),
+        |row| {
+            let phase: Option<String> = row.get(8)?;
+            Ok(Cycle {
+                state: row.get(0)?,
+                level: row.get(1)?,
+                region: row.get(2)?,
+                fuel: row.get(3)?,
+                updated_at: row.get(4)?,
+                cycles: row.get(5)?,
+                length: row.get(6)?,
+                amplitude: row.get(7)?,
+                phase: match phase.as_deref() {
+                    Some("rising") => Some(Phase::Rising),
+                    Some("falling") => Some(Phase::Falling),
+                    _ => None,
+                },
+                last_trough: row.get(9)?,
+                last_peak: row.get(10)?,
+            })
+        },
+    )?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+/// The stored turning points of a region and fuel, oldest first.
+pub fn points(
+    conn: &Connection,
+    state: State,
+    level: &str,
+    region: &str,
+    fuel: Fuel,
+) -> Result<Vec<Turn>> {
+    let mut select = conn.prepare(
+        "select date, kind, price from price_cycle_point
+        where state = ? and level = ? and region = ? and fuel = ?
+        order by date",
+    )?;
+    let rows = select.query_map((state as u8, level, region, fuel as u8), |row| {
+        let kind: String = row.get(1)?;
+        Ok(Turn {
+            date: row.get(0)?,
+            kind: if kind == "peak" {
+                Kind::Peak
+            } else {
+                Kind::Trough
+            },
+            price: row.get(2)?,
+        })
+    })?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Days;
+
+    use super::*;
+    use crate::db;
+
+    fn series(prices: impl IntoIterator<Item = f64>) -> Vec<(NaiveDate, f64)> {
+        let start = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        prices
+            .into_iter()
+            .enumerate()
+            .map(|(i, x)| (start + Days::new(i as u64), x))
+            .collect()
+    }
+
+    /// A weekly hike of 20c followed by 3c a day of discounting.
+    fn sawtooth(weeks: usize) -> Vec<(NaiveDate, f64)> {
+        series((0..weeks * 7).map(|i| match i % 7 {
+            0 => 170.0,
+            x => 190.0 - 3.0 * (x - 1) as f64,
+        }))
+    }
+
+    /// Going up and down by 4c, under [`THRESHOLD`].
+    fn wobble() -> Vec<(NaiveDate, f64)> {
+        series((0..28).map(|i| if i % 2 == 0 { 180.0 } else { 184.0 }))
+    }
+
+    #[test]
+    fn sawtooth_cycles() {
+        let series = sawtooth(4);
+        let (turns, phase) = turning_points(&series, THRESHOLD);
+        let found: Vec<(i64, Kind, f64)> = turns
+            .iter()
+            .map(|x| ((x.date - series[0].0).num_days(), x.kind, x.price))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (0, Kind::Trough, 170.0),
+                (1, Kind::Peak, 190.0),
+                (7, Kind::Trough, 170.0),
+                (8, Kind::Peak, 190.0),
+                (14, Kind::Trough, 170.0),
+                (15, Kind::Peak, 190.0),
+                (21, Kind::Trough, 170.0),
+                (22, Kind::Peak, 190.0),
+            ]
+        );
+        assert_eq!(phase, Some(Phase::Falling));
+
+        let summary = summarise(&turns, phase);
+        assert_eq!(summary.cycles, 3);
+        assert_eq!(summary.length, Some(7.0));
+        assert_eq!(summary.amplitude, Some(20.0));
+        assert_eq!(summary.last_trough, Some(series[21].0));
+        assert_eq!(summary.last_peak, Some(series[22].0));
+    }
+
+    #[test]
+    fn flat_has_no_cycles() {
+        let (turns, phase) = turning_points(&series([180.0; 28]), THRESHOLD);
+        assert!(turns.is_empty());
+        assert_eq!(phase, None);
+
+        let summary = summarise(&turns, phase);
+        assert_eq!(summary.cycles, 0);
+        assert_eq!(summary.length, None);
+        assert_eq!(summary.amplitude, None);
+        assert_eq!(summary.last_trough, None);
+        assert_eq!(summary.last_peak, None);
+    }
+
+    #[test]
+    fn moves_below_the_threshold_are_noise() {
+        let (turns, phase) = turning_points(&wobble(), THRESHOLD);
+        assert!(turns.is_empty());
+        assert_eq!(phase, None);
+
+        // but turn with a lower one
+        let (turns, _) = turning_points(&wobble(), 3.0);
+        let summary = summarise(&turns, None);
+        assert_eq!(summary.length, Some(2.0));
+        assert_eq!(summary.amplitude, Some(4.0));
+    }
+
+    #[test]
+    fn only_the_default_threshold_is_stored() {
+        let conn = db::open_in_memory().unwrap();
+        for (date, median) in wobble() {
+            conn.execute(
+                "insert into price_daily (date, state, level, region, fuel, min, mean, median, max, stations)
+                values (?, ?, 'state', 'NSW', ?, ?2, ?4, ?4, ?4, 1)",
+                (date, State::NSW as u8, Fuel::Unleaded91 as u8, median),
+            )
+            .unwrap();
+        }
+
+        assert_eq!(update(&conn).unwrap(), 1);
+        let detected = detect(&conn, 3.0).unwrap();
+        assert!(detected[0].0.cycles > 0);
+
+        // detecting with another threshold doesn't replace what was stored
+        let stored = load(&conn, None, None, None).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].cycles, 0);
+        let points: usize = conn
+            .query_row("select count(*) from price_cycle_point", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(points, 0);
+    }
+}
@@ -0,0 +1,56 @@
+use std::path::Path;
+
+use anyhow::Result;
+use rusqlite::Connection;
+
+/// Schema changes applied on top of `db.sql`, in order. The number already
+/// applied is tracked in `pragma user_version`.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../migrations/01_station.sql"),
+    include_str!("../migrations/02_price_forecast.sql"),
+    include_str!("../migrations/03_location_flag.sql"),
+    include_str!("../migrations/04_address.sql"),
+    include_str!("../migrations/05_boundary.sql"),
+    include_str!("../migrations/06_price_history_time.sql"),
+    include_str!("../migrations/07_price_snapshot.sql"),
+    include_str!("../migrations/08_price_daily.sql"),
+    include_str!("../migrations/09_price_cycle.sql"),
+    include_str!("../migrations/10_alert.sql"),
+    include_str!("../migrations/11_change_log.sql"),
+    include_str!("../migrations/12_fetch_run.sql"),
+    include_str!("../migrations/13_last_seen.sql"),
+];
+
+pub fn open() -> Result<Connection> {
+    open_path(Path::new("fuel.db"))
+}
+
+pub fn open_path(path: &Path) -> Result<Connection> {
+    let new = !path.exists();
+    let mut conn = Connection::open(path)?;
+    if new {
+        conn.execute_batch(include_str!("../db.sql"))?;
+    }
+    migrate(&mut conn)?;
+    Ok(conn)
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.query_row("pragma user_version", [], |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// A fresh database in memory, for tests.
+#[cfg(test)]
+pub fn open_in_memory() -> Result<Connection> {
+    let mut conn = Connection::open_in_memory()?;
+    conn.execute_batch(include_str!("../db.sql"))?;
+    migrate(&mut conn)?;
+    Ok(conn)
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::Write,
+};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use geojson::{Feature, FeatureCollection, JsonObject, JsonValue};
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::{Fuel, State};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    Csv,
+    /// One json object per line
+    Jsonl,
+}
+
+/// Which price changes to export, everything by default.
+#[derive(Debug, Default)]
+pub struct Filter {
+    pub state: Option<State>,
+    pub fuel: Option<Fuel>,
+    pub station: Option<u32>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Change {
+    state: State,
+    station: u32,
+    fuel: Fuel,
+    changed_at: u64,
+    // cents per liter, empty if the fuel became unavailable
+    price: Option<f64>,
+    brand: Option<String>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    address: Option<String>,
+    locality: Option<String>,
+    postcode: Option<String>,
+    sa2: Option<String>,
+    sa3: Option<String>,
+    sa4: Option<String>,
+    lga: Option<String>,
+    poa: Option<String>,
+}
+
+/// Writes every price change matching `filter` with its station's details,
+/// a row at a time. Returns how many were written.
+pub fn history(
+    conn: &Connection,
+    filter: &Filter,
+    format: Format,
+    output: impl Write,
+) -> Result<usize> {
+    let mut select = conn.prepare(
+        "select h.state, h.station, h.fuel, h.changed_at, h.price,
+            s.brand, s.latitude, s.longitude, s.address, s.locality, s.postcode,
+            s.sa2, s.sa3, s.sa4, s.lga, s.poa
+        from price_history h
+        left join station s on s.state = h.state and s.id = h.station
+        where (?1 is null or h.state = ?1) and (?2 is null or h.fuel = ?2)
+            and (?3 is null or h.station = ?3)
+            and (?4 is null or h.changed_at >= ?4) and (?5 is null or h.changed_at <= ?5)
+        order by h.state, h.station, h.fuel, h.changed_at",
+    )?;
+    let rows = select.query_map(
+        (
+            filter.state.map(|x| x as u8),
+            filter.fuel.map(|x| x as u8),
+            filter.station,
+            filter.from,
+            filter.to,
+        ),
+        |row| {
+            Ok(Change {
+                state: row.get(0)?,
+                station: row.get(1)?,
+                fuel: row.get(2)?,
+                changed_at: row.get(3)?,
+                price: row.get(4)?,
+                brand: row.get(5)?,
+                latitude: row.get(6)?,
+                longitude: row.get(7)?,
+                address: row.get(8)?,
+                locality: row.get(9)?,
+                postcode: row.get(10)?,
+                sa2: row.get(11)?,
+                sa3: row.get(12)?,
+                sa4: row.get(13)?,
+                lga: row.get(14)?,
+                poa: row.get(15)?,
+            })
+        },
+    )?;
+
+    let mut count = 0;
+    match format {
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(output);
+            for row in rows {
+                writer.serialize(row?)?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+        Format::Jsonl => {
+            let mut output = output;
+            for row in rows {
+                serde_json::to_writer(&mut output, &row?)?;
+                output.write_all(b"\n")?;
+                count += 1;
+            }
+            output.flush()?;
+        }
+    }
+    Ok(count)
+}
+
+/// Stations as GeoJSON points, with each current price as a property named
+/// after the fuel (`Unleaded91`) and when it was updated (`Unleaded91_updated_at`).
+/// Stations without a location, or without `fuel` if it's given, are left out.
+pub fn stations_geojson(
+    conn: &Connection,
+    state: Option<State>,
+    fuel: Option<Fuel>,
+) -> Result<FeatureCollection> {
+    let mut features = BTreeMap::new();
+    let mut select = conn.prepare(
+        "select state, id, brand, latitude, longitude, address, locality, postcode from station
+        where (?1 is null or state = ?1) and location_flag is not 'zero'",
+    )?;
+    let mut rows = select.query([state.map(|x| x as u8)])?;
+    while let Some(row) = rows.next()? {
+        let key: (u8, u32) = (row.get(0)?, row.get(1)?);
+        let point = geo::Point::new(row.get::<_, f64>(4)?, row.get::<_, f64>(3)?);
+        let mut properties = JsonObject::new();
+        properties.insert("state".into(), row.get::<_, State>(0)?.as_str().into());
+        properties.insert("id".into(), key.1.into());
+        for (i, name) in [
+            (2, "brand"),
+            (5, "address"),
+            (6, "locality"),
+            (7, "postcode"),
+        ] {
+            properties.insert(name.into(), row.get::<_, Option<String>>(i)?.into());
+        }
+        features.insert(
+            key,
+            Feature {
+                geometry: Some((&point).into()),
+                properties: Some(properties),
+                ..Default::default()
+            },
+        );
+    }
+
+    let mut select = conn.prepare(
+        "select state, station, fuel, price, updated_at from current_price
+        where (?1 is null or state = ?1) and (?2 is null or fuel = ?2) and price is not null",
+    )?;
+    let mut rows = select.query((state.map(|x| x as u8), fuel.map(|x| x as u8)))?;
+    let mut selling = HashSet::new();
+    while let Some(row) = rows.next()? {
+        let key: (u8, u32) = (row.get(0)?, row.get(1)?);
+        let Some(properties) = features.get_mut(&key).and_then(|x| x.properties.as_mut()) else {
+            continue;
+        };
+        let name = row.get::<_, Fuel>(2)?.as_str();
+        properties.insert(name.into(), row.get::<_, f64>(3)?.into());
+        properties.insert(
+            format!("{name}_updated_at"),
+            JsonValue::from(row.get::<_, u64>(4)?),
+        );
+        selling.insert(key);
+    }
+
+    if fuel.is_some() {
+        features.retain(|k, _| selling.contains(k));
+    }
+    Ok(features.into_values().collect())
+}
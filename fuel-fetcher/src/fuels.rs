@@ -0,0 +1,63 @@
+//! Mapping of each source's fuel names to our fuels, see `fuels.toml`. Also
+//! used by fuel-history, which has its own `Fuel` and `fuels.toml`.
+
+use std::{collections::HashMap, fs};
+
+use anyhow::{bail, Context, Result};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::Fuel;
+
+/// Parses the mapping in `path`, or the built-in `default` if none is given.
+pub fn load<T: DeserializeOwned>(path: Option<&str>, default: &str) -> Result<T> {
+    match path {
+        Some(path) => {
+            let data = fs::read_to_string(path)?;
+            toml::from_str(&data).with_context(|| format!("invalid fuel codes in {path}"))
+        }
+        None => Ok(toml::from_str(default).expect("built-in fuels.toml is valid")),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(transparent)]
+pub struct Codes(HashMap<String, Target>);
+
+impl Codes {
+    pub fn contains(&self, code: &str) -> bool {
+        self.0.contains_key(code)
+    }
+
+    /// `None` if the code is explicitly ignored, an error if it isn't listed at all.
+    pub fn get(&self, code: &str) -> Result<Option<Fuel>> {
+        match self.0.get(code) {
+            Some(Target::Fuel(fuel)) => Ok(Some(*fuel)),
+            Some(Target::Ignore) => Ok(None),
+            None => bail!("unknown fuel code: {code}"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(try_from = "String")]
+enum Target {
+    Fuel(Fuel),
+    Ignore,
+}
+
+impl TryFrom<String> for Target {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        if value == "ignore" {
+            return Ok(Self::Ignore);
+        }
+        match value.parse() {
+            Ok(fuel) => Ok(Self::Fuel(fuel)),
+            Err(()) => Err(format!(
+                "unknown fuel {value}, expected \"ignore\" or one of {}",
+                Fuel::all().map(|x| x.as_str()).join(", ")
+            )),
+        }
+    }
+}
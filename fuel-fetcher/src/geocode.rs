@@ -0,0 +1,146 @@
+use std::{collections::HashMap, fs::File, io::BufReader, path::Path};
+
+use anyhow::{bail, Result};
+use geo::{Distance, Haversine, Point};
+use rstar::{primitives::GeomWithData, RTree};
+use rusqlite::Connection;
+
+// metres, anything further away than this isn't the station's address
+const MAX_DISTANCE: f64 = 500.0;
+
+// degrees of latitude in MAX_DISTANCE, with some room for longitude shrinking
+const MAX_DEGREES: f64 = MAX_DISTANCE / 111_320.0 * 1.5;
+
+pub struct Address {
+    pub address: Option<String>,
+    pub locality: String,
+    pub postcode: String,
+}
+
+/// Nearest address lookups from a local file of address points.
+pub struct ReverseGeocoder {
+    // points are (longitude, latitude)
+    tree: RTree<GeomWithData<[f64; 2], usize>>,
+    addresses: Vec<Address>,
+}
+
+impl ReverseGeocoder {
+    /// Loads a CSV of address points, or pipe separated if the file ends in
+    /// `.psv` like a G-NAF extract. Columns are matched by name, ignoring case:
+    ///
+    /// - `latitude`, `longitude`
+    /// - `locality` or `locality_name`
+    /// - `postcode`
+    /// - optionally `address` or `full_address`
+    ///
+    /// A full extract is millions of rows, so only points near `near` are kept.
+    pub fn load(path: &Path, near: &[Point]) -> Result<Self> {
+        let near = RTree::bulk_load(near.iter().map(|x| [x.x(), x.y()]).collect());
+
+        let psv = path
+            .extension()
+            .is_some_and(|x| x.eq_ignore_ascii_case("psv"));
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(if psv { b'|' } else { b',' })
+            .from_reader(BufReader::new(File::open(path)?));
+
+        let headers: HashMap<String, usize> = reader
+            .headers()?
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (x.to_ascii_lowercase(), i))
+            .collect();
+        let column = |names: &[&str]| names.iter().find_map(|x| headers.get(*x).copied());
+        let (Some(latitude), Some(longitude), Some(locality), Some(postcode)) = (
+            column(&["latitude"]),
+            column(&["longitude"]),
+            column(&["locality", "locality_name"]),
+            column(&["postcode"]),
+        ) else {
+            bail!("{path:?} needs latitude, longitude, locality and postcode columns");
+        };
+        let address = column(&["address", "full_address"]);
+
+        let mut points = Vec::new();
+        let mut addresses = Vec::new();
+        for (i, record) in reader.records().enumerate() {
+            let record = record?;
+            let get = |i: usize| record.get(i).unwrap_or("").trim();
+            let point = [get(longitude), get(latitude)];
+            let (Ok(lon), Ok(lat)) = (point[0].parse::<f64>(), point[1].parse::<f64>()) else {
+                // line numbers start at 1 and skip the header
+                bail!("{path:?} line {}: invalid location {point:?}", i + 2);
+            };
+            if near
+                .locate_within_distance([lon, lat], MAX_DEGREES.powi(2))
+                .next()
+                .is_none()
+            {
+                continue;
+            }
+
+            points.push(GeomWithData::new([lon, lat], addresses.len()));
+            addresses.push(Address {
+                address: address
+                    .map(|x| get(x).to_string())
+                    .filter(|x| !x.is_empty()),
+                locality: get(locality).to_string(),
+                postcode: get(postcode).to_string(),
+            });
+        }
+
+        Ok(Self {
+            tree: RTree::bulk_load(points),
+            addresses,
+        })
+    }
+
+    /// The nearest address to `point` (longitude, latitude), if there's one close enough.
+    pub fn lookup(&self, point: Point) -> Option<&Address> {
+        let nearest = self.tree.nearest_neighbor(&[point.x(), point.y()])?;
+        let found = Point::new(nearest.geom()[0], nearest.geom()[1]);
+        if Haversine.distance(point, found) > MAX_DISTANCE {
+            return None;
+        }
+        Some(&self.addresses[nearest.data])
+    }
+}
+
+/// Fills in the address of every station that doesn't have one yet, returning
+/// how many were found.
+pub fn update(conn: &Connection, path: &Path) -> Result<usize> {
+    let mut stations = Vec::new();
+    {
+        let mut select = conn.prepare(
+            "select state, id, latitude, longitude from station
+            where locality is null and not (latitude = 0 and longitude = 0)",
+        )?;
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            let key: (u8, u32) = (row.get(0)?, row.get(1)?);
+            stations.push((key, Point::new(row.get(3)?, row.get(2)?)));
+        }
+    }
+    if stations.is_empty() {
+        return Ok(0);
+    }
+
+    let points: Vec<Point> = stations.iter().map(|x| x.1).collect();
+    let geocoder = ReverseGeocoder::load(path, &points)?;
+
+    let mut found = 0;
+    let tx = conn.unchecked_transaction()?;
+    {
+        let mut update = tx.prepare(
+            "update station set address = ?, locality = ?, postcode = ? where state = ? and id = ?",
+        )?;
+        for ((state, id), point) in stations {
+            if let Some(x) = geocoder.lookup(point) {
+                update.execute((&x.address, &x.locality, &x.postcode, state, id))?;
+                found += 1;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(found)
+}
@@ -1,135 +1,605 @@
 use std::{
     fs,
-    path::Path,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
     str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, FixedOffset, NaiveDate};
 use clap::{Parser, Subcommand};
 use geo::Point;
 use rusqlite::{
     types::{FromSql, FromSqlError},
-    Connection, OptionalExtension, ToSql,
+    Connection, ToSql,
 };
 use serde::{Deserialize, Serialize};
 use ureq::{Agent, AgentBuilder};
 
+mod aggregate;
+mod alerts;
+mod api;
+mod board;
+mod boundary;
+mod changes;
+mod coords;
+mod cycles;
+mod db;
+mod export;
+mod fuels;
+mod geocode;
+mod mqtt;
+mod nearest;
 mod nsw_tas;
 mod nt;
+mod parquet_export;
+mod partition;
 mod qld_sa;
+mod recommend;
+mod route;
+mod store;
 mod wa;
 
 #[derive(Debug, Parser)]
 struct Cli {
     #[clap(short, long)]
     auth_file: Option<String>,
+    /// Fuel code mapping, defaults to the built-in fuels.toml
+    #[clap(short, long)]
+    fuels_file: Option<String>,
     #[clap(subcommand)]
     command: Command,
 }
 
 #[derive(Debug, Subcommand)]
 enum Command {
-    Stations,
-    Prices,
+    Stations {
+        /// Address points to fill in station addresses from, see geocode.rs
+        #[clap(long)]
+        addresses: Option<PathBuf>,
+    },
+    Prices {
+        /// Alert rules to check once the prices are stored, see alerts.rs
+        #[clap(long)]
+        alerts: Option<PathBuf>,
+        /// MQTT broker to publish the changes to, see mqtt.rs
+        #[clap(long)]
+        mqtt: Option<PathBuf>,
+    },
+    /// Check the stored station locations, fixing swapped coordinates
+    CheckStations,
+    /// Import boundary polygons and assign stations to them
+    Boundaries {
+        #[clap(value_enum)]
+        level: boundary::Level,
+        /// GeoJSON file or shapefile, like the ABS downloads
+        path: PathBuf,
+        /// Property with the boundary's code, defaults to one like SA2_CODE21
+        #[clap(long)]
+        code_field: Option<String>,
+        /// Property with the boundary's name, defaults to one like SA2_NAME21
+        #[clap(long)]
+        name_field: Option<String>,
+    },
+    /// Find the cheapest stations near a location
+    Nearest {
+        #[clap(long, allow_hyphen_values = true)]
+        lat: f64,
+        #[clap(long, allow_hyphen_values = true)]
+        lon: f64,
+        #[clap(long, value_parser = parse_fuel)]
+        fuel: Fuel,
+        /// Search radius in km
+        #[clap(long, default_value_t = 10.0)]
+        radius: f64,
+        /// Cents per liter a km of detour is worth, 0 ranks by price only
+        #[clap(long, default_value_t = 0.0)]
+        detour_cost: f64,
+        #[clap(long, default_value_t = 10)]
+        limit: usize,
+    },
+    /// Whether to fill up now or wait, for the cheapest station near a location
+    Recommend {
+        #[clap(long, allow_hyphen_values = true)]
+        lat: f64,
+        #[clap(long, allow_hyphen_values = true)]
+        lon: f64,
+        #[clap(long, value_parser = parse_fuel)]
+        fuel: Fuel,
+        /// Search radius in km
+        #[clap(long, default_value_t = 10.0)]
+        radius: f64,
+    },
+    /// Find the cheapest stations along a route
+    Route {
+        /// GPX or GeoJSON file with the route
+        path: PathBuf,
+        #[clap(long, value_parser = parse_fuel)]
+        fuel: Fuel,
+        /// Furthest a station can be from the route in km
+        #[clap(long, default_value_t = 5.0)]
+        max_detour: f64,
+        #[clap(long, default_value_t = 10)]
+        limit: usize,
+    },
+    /// Show every station's price at a point in time
+    AsOf {
+        /// Unix seconds or RFC 3339
+        #[clap(value_parser = parse_time)]
+        time: u64,
+        #[clap(long, value_parser = parse_state)]
+        state: Option<State>,
+        #[clap(long, value_parser = parse_fuel)]
+        fuel: Option<Fuel>,
+    },
+    /// Materialise price board snapshots to speed up as-of
+    Snapshots {
+        /// Hours between snapshots
+        #[clap(long, default_value_t = 24, value_parser = clap::value_parser!(u64).range(1..))]
+        every: u64,
+    },
+    /// Recompute the daily price statistics from scratch
+    RebuildDaily,
+    /// Detect price cycles in the daily statistics and show them
+    Cycles {
+        #[clap(long, value_parser = parse_state)]
+        state: Option<State>,
+        #[clap(long, value_parser = parse_fuel)]
+        fuel: Option<Fuel>,
+        /// state or sa4
+        #[clap(long)]
+        level: Option<String>,
+        /// Cents per liter prices must move to count as a trough or peak, the
+        /// cycles are only stored with the default
+        #[clap(long, default_value_t = cycles::THRESHOLD)]
+        threshold: f64,
+    },
+    /// Check alert rules against the stored prices
+    Alerts {
+        /// TOML file with the rules and sinks, see alerts.rs
+        path: PathBuf,
+    },
+    /// Print price and station changes after a cursor as json lines
+    Changes {
+        /// Cursor from a previous run, 0 for everything since the log was added
+        #[clap(long, default_value_t = 0)]
+        since: i64,
+        #[clap(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..=changes::MAX_LIMIT as u64))]
+        limit: u64,
+    },
+    /// Export price history with station details
+    Export {
+        #[clap(long, value_enum, default_value = "csv")]
+        format: export::Format,
+        /// Defaults to stdout
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+        #[clap(long, value_parser = parse_state)]
+        state: Option<State>,
+        #[clap(long, value_parser = parse_fuel)]
+        fuel: Option<Fuel>,
+        #[clap(long)]
+        station: Option<u32>,
+        /// Unix seconds or RFC 3339
+        #[clap(long, value_parser = parse_time)]
+        from: Option<u64>,
+        /// Unix seconds or RFC 3339
+        #[clap(long, value_parser = parse_time)]
+        to: Option<u64>,
+    },
+    /// Export stations and their current prices as GeoJSON
+    ExportGeojson {
+        /// Defaults to stdout
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+        #[clap(long, value_parser = parse_state)]
+        state: Option<State>,
+        /// Only stations selling this fuel, with only its price
+        #[clap(long, value_parser = parse_fuel)]
+        fuel: Option<Fuel>,
+    },
+    /// Export price history as Parquet, partitioned by state and month
+    Parquet {
+        /// Partitions already here are only written again if they were incomplete
+        dir: PathBuf,
+    },
+    /// Serve a json API over the database
+    Serve {
+        #[clap(short, long, default_value = "127.0.0.1:8080")]
+        listen: String,
+    },
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
-    let auth: Auth = toml::from_str(&fs::read_to_string(
-        cli.auth_file.as_deref().unwrap_or("auth.toml"),
-    )?)?;
+    let auth = || -> Result<Auth> {
+        Ok(toml::from_str(&fs::read_to_string(
+            cli.auth_file.as_deref().unwrap_or("auth.toml"),
+        )?)?)
+    };
+    let codes = FuelCodes::load(cli.fuels_file.as_deref())?;
 
     match cli.command {
-        Command::Stations => {
+        Command::Stations { addresses } => {
+            let auth = auth()?;
+            let conn = db::open()?;
             let mut stations = Vec::new();
             for state in State::all() {
                 eprintln!("Fetching {}", state.as_str());
-                stations.extend(state.stations(&auth)?);
+                stations.extend(state.stations(&auth, &conn)?);
             }
 
-            for station in stations {
-                let (x, y) = station.point.x_y();
-                let x = ureq::get(&format!("https://api.joel.net.au/gnafr/{x}/{y}"))
-                    .call()?
-                    .into_string()?;
-                println!("{x}");
+            let tx = conn.unchecked_transaction()?;
+            store::stations(&tx, &mut stations, now())?;
+            tx.commit()?;
+
+            if let Some(path) = addresses {
+                eprintln!("Geocoding");
+                let found = geocode::update(&conn, &path)?;
+                eprintln!("Found {found} new addresses");
+            }
+            for level in boundary::Level::all() {
+                boundary::assign(&conn, level)?;
             }
             // fs::write("stations.json", serde_json::to_string_pretty(&stations)?)?;
         }
 
-        Command::Prices => {
+        Command::CheckStations => {
+            let conn = db::open()?;
+            let mut stations = Vec::new();
+            {
+                let mut select =
+                    conn.prepare("select state, id, brand, latitude, longitude from station")?;
+                let mut rows = select.query([])?;
+                while let Some(row) = rows.next()? {
+                    stations.push(Station {
+                        state: row.get(0)?,
+                        id: row.get(1)?,
+                        brand: row.get(2)?,
+                        point: Point::new(row.get(4)?, row.get(3)?),
+                    });
+                }
+            }
+
+            let tx = conn.unchecked_transaction()?;
+            {
+                let mut update = tx.prepare(
+                    "update station set latitude = ?1, longitude = ?2, location_flag = ?3,
+                        address = iif(latitude = ?1 and longitude = ?2, address, null),
+                        locality = iif(latitude = ?1 and longitude = ?2, locality, null),
+                        postcode = iif(latitude = ?1 and longitude = ?2, postcode, null),
+                        sa2 = iif(latitude = ?1 and longitude = ?2, sa2, null),
+                        sa3 = iif(latitude = ?1 and longitude = ?2, sa3, null),
+                        sa4 = iif(latitude = ?1 and longitude = ?2, sa4, null),
+                        lga = iif(latitude = ?1 and longitude = ?2, lga, null),
+                        poa = iif(latitude = ?1 and longitude = ?2, poa, null)
+                    where state = ?4 and id = ?5",
+                )?;
+                for station in &mut stations {
+                    let problem = coords::check(station);
+                    if let Some(problem) = problem {
+                        report(station, problem);
+                    }
+                    let (longitude, latitude) = station.point.x_y();
+                    update.execute((
+                        latitude,
+                        longitude,
+                        problem.map(|x| x.as_str()),
+                        station.state as u8,
+                        station.id,
+                    ))?;
+                }
+            }
+            tx.commit()?;
+
+            for level in boundary::Level::all() {
+                boundary::assign(&conn, level)?;
+            }
+        }
+
+        Command::Boundaries {
+            level,
+            path,
+            code_field,
+            name_field,
+        } => {
+            let conn = db::open()?;
+            let count = boundary::import(
+                &conn,
+                level,
+                &path,
+                code_field.as_deref(),
+                name_field.as_deref(),
+            )?;
+            let assigned = boundary::assign(&conn, level)?;
+            eprintln!("Imported {count} boundaries, {assigned} stations are inside one");
+        }
+
+        Command::Prices { alerts, mqtt } => {
+            // a mistake in the config should show up before fetching, not after
+            let alerts = alerts.map(|x| alerts::Config::load(&x)).transpose()?;
+            let mqtt = mqtt.map(|x| mqtt::Config::load(&x)).transpose()?;
+            let auth = auth()?;
+            let conn = db::open()?;
             let mut failed = false;
-            let mut prices = Vec::new();
+            let mut changes = 0;
+            // everything written to price_history, to publish
+            let mut changed = Vec::new();
 
+            // each state is stored as soon as it's fetched, with its own time
+            // and transaction, so one being slow or failing doesn't affect the rest
             for state in State::all() {
                 eprintln!("Fetching {}", state.as_str());
-                match state.prices(&auth) {
-                    Ok(x) => prices.extend(x),
+                let started_at = now();
+                let attempts = HTTP_ATTEMPTS.load(Ordering::Relaxed);
+                let result = state.prices(&auth, &codes, &conn);
+                let mut run = FetchRun {
+                    source: state,
+                    started_at,
+                    finished_at: now(),
+                    http_attempts: HTTP_ATTEMPTS.load(Ordering::Relaxed) - attempts,
+                    rows_parsed: None,
+                    error: None,
+                };
+                let result = result.and_then(|prices| {
+                    run.rows_parsed = Some(prices.current.len() + prices.forecast.len());
+                    store::prices(&conn, &run, prices).context("failed to store prices")
+                });
+                match result {
+                    Ok((count, x)) => {
+                        changes += count;
+                        changed.extend(x);
+                    }
                     Err(e) => {
-                        eprintln!("{} failed: {e}", state.as_str());
+                        eprintln!("{} failed: {e:#}", state.as_str());
                         failed = true;
+                        run.error = Some(format!("{e:#}"));
+                        // likely to fail too if the DB was the problem, which
+                        // shouldn't stop the other states
+                        if let Err(e) = run.record(&conn) {
+                            eprintln!("Failed to record the {} run: {e:#}", state.as_str());
+                        }
                     }
-                };
+                }
             }
-
-            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-
-            let path = Path::new("fuel.db");
-            let new = !path.exists();
-            let mut conn = Connection::open(path)?;
-            if new {
-                conn.execute_batch(include_str!("../db.sql"))?;
+            eprintln!("{changes} changes were recorded");
+            // for WA stations stored along with their prices
+            for level in boundary::Level::all() {
+                boundary::assign(&conn, level)?;
             }
 
-            eprintln!("Updating DB");
-            let mut changes = 0usize;
-            let tx = conn.transaction()?;
-            {
-                let mut select = tx.prepare(
-                    "select price from price where state = ? and station = ? and fuel = ?",
-                )?;
-                let mut insert = tx.prepare(
-                    "insert into price (state, station, fuel, updated_at, price) values (?, ?, ?, ?, ?)")
-                ?;
-                let mut history = tx.prepare(
-                    "insert into price_history (state, station, fuel, changed_at, price) values (?, ?, ?, ?, ?)"
-                )?;
-                let mut update = tx.prepare(
-                    "update price set updated_at = ?, price = ? where state = ? and station = ? and fuel = ?",
-                )?;
-
-                for price in prices {
-                    let state = price.state as u8;
-                    let fuel = price.fuel as u8;
-
-                    // first option: row found?
-                    // second option: fuel available?
-                    let db_price: Option<Option<f64>> = select
-                        .query_row((&state, &price.station, &fuel), |row| row.get(0))
-                        .optional()?;
-
-                    if let Some(db_price) = db_price {
-                        update.execute((&now, &price.price, &state, &price.station, &fuel))?;
-                        if price.price != db_price {
-                            changes += 1;
-                            history.execute((&state, &price.station, &fuel, &now, &price.price))?;
-                        }
-                    } else {
-                        insert.execute((&state, &price.station, &fuel, &now, &price.price))?;
-                        history.execute((&state, &price.station, &fuel, &now, &price.price))?;
+            let days = aggregate::update(&conn)?;
+            eprintln!("Updated {days} days of statistics");
+            cycles::update(&conn)?;
+            if let Some(config) = &alerts {
+                let sent = alerts::run(&conn, config)?;
+                eprintln!("Sent {sent} alerts");
+            }
+            if let Some(config) = &mqtt {
+                // the prices are stored, so a broker being down shouldn't stop the rest
+                match mqtt::publish(&conn, config, &changed) {
+                    Ok(x) => eprintln!("Published {x} MQTT messages"),
+                    Err(e) => {
+                        eprintln!("MQTT failed: {e:#}");
+                        failed = true;
                     }
                 }
             }
 
-            tx.commit()?;
-            eprintln!("{changes} changes were recorded");
-
             if failed {
                 // grafana will notify me that this systemd unit failed
                 bail!("A fetcher failed");
             }
         }
+
+        Command::Nearest {
+            lat,
+            lon,
+            fuel,
+            radius,
+            detour_cost,
+            limit,
+        } => {
+            let conn = db::open()?;
+            let origin = Point::new(lon, lat);
+            for x in nearest::nearest(&conn, origin, fuel, radius, detour_cost, limit)? {
+                println!(
+                    "{:.1} {:.1}km {} {} {}",
+                    x.price,
+                    x.distance,
+                    x.state.as_str(),
+                    x.station,
+                    x.brand.as_deref().unwrap_or("")
+                );
+            }
+        }
+
+        Command::Recommend {
+            lat,
+            lon,
+            fuel,
+            radius,
+        } => {
+            let conn = db::open()?;
+            let origin = Point::new(lon, lat);
+            let index = nearest::StationIndex::load(&conn)?;
+            match recommend::recommend(&conn, &index, origin, fuel, radius)? {
+                Some(x) => println!(
+                    "{}: {} {} {} at {:.1}, saving {:.1} ({:.0}% confident), {}",
+                    x.action.as_str(),
+                    x.station.state.as_str(),
+                    x.station.station,
+                    x.station.brand.as_deref().unwrap_or(""),
+                    x.station.price,
+                    x.saving,
+                    x.confidence * 100.0,
+                    x.reason
+                ),
+                None => bail!("No stations selling {} nearby", fuel.as_str()),
+            }
+        }
+
+        Command::Route {
+            path,
+            fuel,
+            max_detour,
+            limit,
+        } => {
+            let conn = db::open()?;
+            let line = route::load(&path)?;
+            for x in route::along(&conn, &line, fuel, max_detour, limit)? {
+                println!(
+                    "{:.1} {:.1}km (+{:.1}km) {} {} {}",
+                    x.price,
+                    x.along,
+                    x.detour,
+                    x.state.as_str(),
+                    x.station,
+                    x.brand.as_deref().unwrap_or("")
+                );
+            }
+        }
+
+        Command::AsOf { time, state, fuel } => {
+            let conn = db::open()?;
+            for x in board::at(&conn, time, state, fuel)? {
+                let price = x.price.map(|x| format!("{x:.1}"));
+                println!(
+                    "{} {} {} {} {}",
+                    x.state.as_str(),
+                    x.station,
+                    x.fuel.as_str(),
+                    price.as_deref().unwrap_or("unavailable"),
+                    x.changed_at
+                );
+            }
+        }
+
+        Command::Snapshots { every } => {
+            let conn = db::open()?;
+            let taken = board::snapshots(&conn, every * 60 * 60)?;
+            eprintln!("Took {} snapshots", taken.len());
+        }
+
+        Command::Alerts { path } => {
+            let config = alerts::Config::load(&path)?;
+            let conn = db::open()?;
+            let sent = alerts::run(&conn, &config)?;
+            eprintln!("Sent {sent} alerts");
+        }
+
+        Command::Changes { since, limit } => {
+            let conn = db::open()?;
+            let changes = changes::since(&conn, since, limit as usize)?;
+            let mut output = BufWriter::new(io::stdout().lock());
+            for x in &changes {
+                serde_json::to_writer(&mut output, x)?;
+                writeln!(output)?;
+            }
+            output.flush()?;
+            let cursor = changes.last().map_or(since, |x| x.cursor);
+            eprintln!("{} changes, next cursor {cursor}", changes.len());
+        }
+
+        Command::RebuildDaily => {
+            let conn = db::open()?;
+            let days = aggregate::rebuild(&conn)?;
+            eprintln!("Rebuilt {days} days of statistics");
+        }
+
+        Command::Cycles {
+            state,
+            fuel,
+            level,
+            threshold,
+        } => {
+            let conn = db::open()?;
+            let cycles = if threshold == cycles::THRESHOLD {
+                cycles::update(&conn)?;
+                cycles::load(&conn, state, fuel, level.as_deref())?
+            } else {
+                // only shown, what's stored is what everything else uses
+                cycles::detect(&conn, threshold)?
+                    .into_iter()
+                    .map(|(x, _)| x)
+                    .filter(|x| {
+                        state.is_none_or(|y| y as u8 == x.state as u8)
+                            && fuel.is_none_or(|y| y as u8 == x.fuel as u8)
+                            && level.as_ref().is_none_or(|y| *y == x.level)
+                    })
+                    .collect()
+            };
+            for x in cycles {
+                let days = |x: Option<f64>| x.map_or("-".into(), |x| format!("{x:.0}d"));
+                let cents = |x: Option<f64>| x.map_or("-".into(), |x| format!("{x:.1}c"));
+                println!(
+                    "{} {} {} {} cycles={} length={} amplitude={} phase={} last_trough={}",
+                    x.state.as_str(),
+                    x.region,
+                    x.fuel.as_str(),
+                    x.level,
+                    x.cycles,
+                    days(x.length),
+                    cents(x.amplitude),
+                    x.phase.map_or("-", |x| x.as_str()),
+                    x.last_trough.map_or("-".into(), |x| x.to_string()),
+                );
+            }
+        }
+
+        Command::Export {
+            format,
+            output,
+            state,
+            fuel,
+            station,
+            from,
+            to,
+        } => {
+            let conn = db::open()?;
+            let filter = export::Filter {
+                state,
+                fuel,
+                station,
+                from,
+                to,
+            };
+            let output: Box<dyn Write> = match output {
+                Some(path) => Box::new(fs::File::create(path)?),
+                None => Box::new(io::stdout().lock()),
+            };
+            let count = export::history(&conn, &filter, format, BufWriter::new(output))?;
+            eprintln!("Exported {count} changes");
+        }
+
+        Command::ExportGeojson {
+            output,
+            state,
+            fuel,
+        } => {
+            let conn = db::open()?;
+            let stations = export::stations_geojson(&conn, state, fuel)?;
+            let count = stations.features.len();
+            let output: Box<dyn Write> = match output {
+                Some(path) => Box::new(fs::File::create(path)?),
+                None => Box::new(io::stdout().lock()),
+            };
+            serde_json::to_writer(BufWriter::new(output), &stations)?;
+            eprintln!("Exported {count} stations");
+        }
+
+        Command::Parquet { dir } => {
+            let conn = db::open()?;
+            let written = parquet_export::export(&conn, &dir)?;
+            for path in &written {
+                eprintln!("Wrote {path:?}");
+            }
+            eprintln!("{} partitions were written", written.len());
+        }
+
+        Command::Serve { listen } => api::serve(&listen)?,
     }
 
     Ok(())
@@ -139,6 +609,24 @@ fn main() -> Result<()> {
 struct Auth {
     qld_token: String,
     sa_token: String,
+    /// Overrides the MyFuel NT results page, can also be a json endpoint
+    nt_url: Option<String>,
+}
+
+/// Per-source mapping of fuel codes, see `fuels.toml`.
+#[derive(Deserialize)]
+struct FuelCodes {
+    nsw_tas: fuels::Codes,
+    nt: fuels::Codes,
+    qld_sa: fuels::Codes,
+    wa: fuels::Codes,
+}
+
+impl FuelCodes {
+    /// Loads the mapping from `path`, or the built-in `fuels.toml` if none is given.
+    fn load(path: Option<&str>) -> Result<Self> {
+        fuels::load(path, include_str!("../fuels.toml"))
+    }
 }
 
 #[derive(Debug)]
@@ -150,8 +638,68 @@ struct CurrentPrice {
     price: Option<f64>,
 }
 
+/// What happened fetching one source, see `fetch_run`.
+struct FetchRun {
+    source: State,
+    started_at: u64,
+    finished_at: u64,
+    http_attempts: usize,
+    // none if the fetch failed
+    rows_parsed: Option<usize>,
+    error: Option<String>,
+}
+
+impl FetchRun {
+    /// Adds the run with no changes yet, returning its id.
+    fn record(&self, conn: &Connection) -> Result<i64> {
+        conn.execute(
+            "insert into fetch_run (source, started_at, finished_at, http_attempts, rows_parsed, changes, error)
+            values (?, ?, ?, ?, ?, 0, ?)",
+            (
+                self.source as u8,
+                self.started_at,
+                self.finished_at,
+                self.http_attempts,
+                self.rows_parsed,
+                &self.error,
+            ),
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+}
+
+/// A price that takes effect on a later date.
+#[derive(Debug)]
+struct ForecastPrice {
+    state: State,
+    station: u32,
+    fuel: Fuel,
+    date: NaiveDate,
+    // cents per liter
+    price: f64,
+}
+
+/// Everything fetched from a source's prices endpoint.
+#[derive(Debug, Default)]
+struct Prices {
+    current: Vec<CurrentPrice>,
+    forecast: Vec<ForecastPrice>,
+    /// For sources that list stations along with their prices, only WA
+    stations: Vec<Station>,
+}
+
+impl From<Vec<CurrentPrice>> for Prices {
+    fn from(current: Vec<CurrentPrice>) -> Self {
+        Self {
+            current,
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 #[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
 enum State {
     NSW,
     NT,
@@ -179,25 +727,38 @@ impl State {
         [NSW, NT, QLD, SA, TAS, WA]
     }
 
-    pub fn prices(&self, auth: &Auth) -> Result<Vec<CurrentPrice>> {
+    /// Standard time, daylight saving is ignored so days are always 24 hours.
+    pub fn utc_offset(&self) -> FixedOffset {
+        let minutes = match self {
+            Self::NSW | Self::QLD | Self::TAS => 10 * 60,
+            Self::NT | Self::SA => 9 * 60 + 30,
+            Self::WA => 8 * 60,
+        };
+        FixedOffset::east_opt(minutes * 60).expect("hardcoded")
+    }
+
+    pub fn prices(&self, auth: &Auth, codes: &FuelCodes, conn: &Connection) -> Result<Prices> {
         match self {
-            Self::NSW => nsw_tas::prices(*self),
-            Self::NT => nt::prices(),
-            Self::QLD => qld_sa::prices(*self, &auth.qld_token),
-            Self::SA => qld_sa::prices(*self, &auth.sa_token),
-            Self::TAS => nsw_tas::prices(*self),
-            Self::WA => wa::prices(),
+            Self::NSW => nsw_tas::prices(*self, &codes.nsw_tas).map(Into::into),
+            Self::NT => nt::prices(&codes.nt, auth.nt_url.as_deref()).map(Into::into),
+            Self::QLD => {
+                qld_sa::prices(*self, &auth.qld_token, &codes.qld_sa, conn).map(Into::into)
+            }
+            Self::SA => qld_sa::prices(*self, &auth.sa_token, &codes.qld_sa, conn).map(Into::into),
+            Self::TAS => nsw_tas::prices(*self, &codes.nsw_tas).map(Into::into),
+            Self::WA => wa::data(&codes.wa),
         }
     }
 
-    pub fn stations(&self, auth: &Auth) -> Result<Vec<Station>> {
+    pub fn stations(&self, auth: &Auth, conn: &Connection) -> Result<Vec<Station>> {
         match self {
             Self::NSW => nsw_tas::stations(*self),
-            Self::NT => nt::stations(),
-            Self::QLD => qld_sa::stations(*self, &auth.qld_token),
-            Self::SA => qld_sa::stations(*self, &auth.sa_token),
+            Self::NT => nt::stations(auth.nt_url.as_deref()),
+            Self::QLD => qld_sa::stations(*self, &auth.qld_token, conn),
+            Self::SA => qld_sa::stations(*self, &auth.sa_token, conn),
             Self::TAS => nsw_tas::stations(*self),
-            Self::WA => wa::stations(),
+            // stored by `prices`, from the same requests as the prices
+            Self::WA => Ok(Vec::new()),
         }
     }
 }
@@ -224,17 +785,36 @@ impl ToSql for State {
     }
 }
 
+impl TryFrom<u8> for State {
+    type Error = ();
+
+    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+        Self::all()
+            .into_iter()
+            .find(|x| *x as u8 == value)
+            .ok_or(())
+    }
+}
+
 impl FromSql for State {
     fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
-        Ok(value
+        // stored as `State as u8` in most tables
+        if let rusqlite::types::ValueRef::Integer(x) = value {
+            return u8::try_from(x)
+                .ok()
+                .and_then(|x| x.try_into().ok())
+                .ok_or(FromSqlError::OutOfRange(x));
+        }
+        value
             .as_str()?
             .parse()
-            .map_err(|_| FromSqlError::InvalidType)?)
+            .map_err(|_| FromSqlError::InvalidType)
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 #[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
 enum Fuel {
     Diesel,
     PremiumDiesel,
@@ -259,6 +839,20 @@ impl Fuel {
             Self::Unleaded98 => "Unleaded98",
         }
     }
+
+    pub const fn all() -> [Fuel; 8] {
+        use Fuel::*;
+        [
+            Diesel,
+            PremiumDiesel,
+            LPG,
+            Ethanol10,
+            Ethanol85,
+            Unleaded91,
+            Unleaded95,
+            Unleaded98,
+        ]
+    }
 }
 
 impl FromStr for Fuel {
@@ -285,29 +879,114 @@ impl ToSql for Fuel {
     }
 }
 
+impl TryFrom<u8> for Fuel {
+    type Error = ();
+
+    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+        Self::all()
+            .into_iter()
+            .find(|x| *x as u8 == value)
+            .ok_or(())
+    }
+}
+
 impl FromSql for Fuel {
     fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
-        Ok(value
+        // stored as `Fuel as u8` in most tables
+        if let rusqlite::types::ValueRef::Integer(x) = value {
+            return u8::try_from(x)
+                .ok()
+                .and_then(|x| x.try_into().ok())
+                .ok_or(FromSqlError::OutOfRange(x));
+        }
+        value
             .as_str()?
             .parse()
-            .map_err(|_| FromSqlError::InvalidType)?)
+            .map_err(|_| FromSqlError::InvalidType)
     }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct Station {
     state: State,
     id: u32,
+    brand: Option<String>,
+    /// x is longitude, y is latitude
     #[serde(flatten)]
     point: Point,
 }
 
+fn report(station: &Station, problem: coords::Problem) {
+    let (longitude, latitude) = station.point.x_y();
+    let name = format!("{} station {}", station.state.as_str(), station.id);
+    match problem {
+        coords::Problem::Zero => eprintln!("{name} has no location"),
+        coords::Problem::Swapped => eprintln!("{name} had latitude and longitude swapped"),
+        coords::Problem::WrongState(x) => {
+            eprintln!("{name} at {latitude}, {longitude} is in {}", x.as_str())
+        }
+        coords::Problem::Outside => {
+            eprintln!("{name} at {latitude}, {longitude} is outside every state we cover")
+        }
+    }
+}
+
 const USER_AGENT: &str = concat!(
     "priceshark-fuel/",
     env!("CARGO_PKG_VERSION"),
     " (mailto:automated@joel.net.au +https://github.com/priceshark/fuel)"
 );
 
+fn parse_state(value: &str) -> std::result::Result<State, String> {
+    value.parse().map_err(|()| {
+        let all = State::all().map(|x| x.as_str()).join(", ");
+        format!("unknown state {value}, expected one of {all}")
+    })
+}
+
+fn parse_fuel(value: &str) -> std::result::Result<Fuel, String> {
+    value.parse().map_err(|()| {
+        let all = Fuel::all().map(|x| x.as_str()).join(", ");
+        format!("unknown fuel {value}, expected one of {all}")
+    })
+}
+
+/// Parses unix seconds or an RFC 3339 timestamp.
+fn parse_time(value: &str) -> Result<u64> {
+    if let Ok(x) = value.parse() {
+        return Ok(x);
+    }
+    let time = DateTime::parse_from_rfc3339(value)
+        .with_context(|| format!("invalid time {value:?}, expected unix seconds or RFC 3339"))?;
+    Ok(time.timestamp().try_into()?)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is after 1970")
+        .as_secs()
+}
+
+/// Requests made by every agent so far, for recording in `fetch_run`.
+static HTTP_ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+
+struct CountAttempts;
+
+impl ureq::Middleware for CountAttempts {
+    fn handle(
+        &self,
+        request: ureq::Request,
+        next: ureq::MiddlewareNext,
+    ) -> Result<ureq::Response, ureq::Error> {
+        HTTP_ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+        next.handle(request)
+    }
+}
+
 fn agent() -> Agent {
-    AgentBuilder::new().user_agent(USER_AGENT).build()
+    AgentBuilder::new()
+        .user_agent(USER_AGENT)
+        .middleware(CountAttempts)
+        .build()
 }
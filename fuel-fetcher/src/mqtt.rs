@@ -0,0 +1,246 @@
+use std::{fs, path::Path, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use rumqttc::{Client, Event, Incoming, MqttOptions, Outgoing, QoS};
+use rusqlite::{Connection, OptionalExtension};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{board::BoardPrice, State};
+
+// how long the broker has to acknowledge everything before giving up
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where to publish price changes, from a TOML file like:
+///
+/// ```toml
+/// host = "localhost"
+/// username = "fuel"
+/// password = "..."
+///
+/// [[favourite]]
+/// state = "NSW"
+/// station = 1234
+/// ```
+#[derive(Deserialize)]
+pub struct Config {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Topics are `{prefix}/{state}/{station}/{fuel}`
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// Stations to add to Home Assistant as devices with a sensor per fuel
+    #[serde(default, rename = "favourite")]
+    pub favourites: Vec<Favourite>,
+}
+
+#[derive(Deserialize)]
+pub struct Favourite {
+    pub state: State,
+    pub station: u32,
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_prefix() -> String {
+    "fuel".into()
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".into()
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path)?;
+        toml::from_str(&data).with_context(|| format!("invalid MQTT config in {path:?}"))
+    }
+}
+
+struct Message {
+    topic: String,
+    payload: String,
+}
+
+/// Publishes `changes` as retained messages, along with the current prices of
+/// the favourite stations and their Home Assistant discovery config, so they
+/// show up even if nothing changed since. Returns how many were published.
+pub fn publish(conn: &Connection, config: &Config, changes: &[BoardPrice]) -> Result<usize> {
+    let mut messages: Vec<Message> = changes.iter().map(|x| price(config, x)).collect();
+    for favourite in &config.favourites {
+        messages.extend(discovery(conn, config, favourite)?);
+        for x in current(conn, favourite)? {
+            messages.push(price(config, &x));
+        }
+    }
+    if messages.is_empty() {
+        return Ok(0);
+    }
+
+    let mut options = MqttOptions::new(
+        format!("fuel-fetcher-{}", std::process::id()),
+        &config.host,
+        config.port,
+    );
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.as_deref().unwrap_or(""));
+    }
+    // room for every message, so publishing doesn't wait for the connection
+    let (client, mut connection) = Client::new(options, messages.len() + 1);
+    for x in &messages {
+        client.publish(&x.topic, QoS::AtLeastOnce, true, x.payload.as_bytes())?;
+    }
+
+    let mut acked = 0;
+    loop {
+        let event = match connection.recv_timeout(TIMEOUT) {
+            Ok(x) => x.context("MQTT connection failed")?,
+            Err(_) => bail!(
+                "MQTT broker acknowledged {acked} of {} messages",
+                messages.len()
+            ),
+        };
+        match event {
+            Event::Incoming(Incoming::PubAck(_)) => {
+                acked += 1;
+                if acked == messages.len() {
+                    client.disconnect()?;
+                }
+            }
+            Event::Outgoing(Outgoing::Disconnect) => break,
+            _ => {}
+        }
+    }
+    Ok(messages.len())
+}
+
+fn topic(config: &Config, state: State, station: u32) -> String {
+    format!("{}/{}/{station}", config.prefix, state.as_str())
+}
+
+fn price(config: &Config, x: &BoardPrice) -> Message {
+    Message {
+        topic: format!("{}/{}", topic(config, x.state, x.station), x.fuel.as_str()),
+        payload: json!({ "price": x.price, "changed_at": x.changed_at }).to_string(),
+    }
+}
+
+/// A sensor for each fuel the station sells, grouped into a device.
+fn discovery(conn: &Connection, config: &Config, favourite: &Favourite) -> Result<Vec<Message>> {
+    let state = favourite.state;
+    let station: Option<(Option<String>, Option<String>)> = conn
+        .query_row(
+            "select brand, coalesce(address || ', ' || locality, locality) from station
+            where state = ? and id = ?",
+            (state as u8, favourite.station),
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    let Some((brand, address)) = station else {
+        eprintln!(
+            "Favourite station {} {} doesn't exist",
+            state.as_str(),
+            favourite.station
+        );
+        return Ok(Vec::new());
+    };
+
+    let id = format!("fuel_{}_{}", state.as_str(), favourite.station).to_lowercase();
+    // only QLD and SA stations have brands
+    let brand = brand.unwrap_or_else(|| format!("{} {}", state.as_str(), favourite.station));
+    let device = json!({
+        "identifiers": [id],
+        "name": match address {
+            Some(x) => format!("{brand} {x}"),
+            None => brand.clone(),
+        },
+        "manufacturer": brand,
+    });
+    let mut messages = Vec::new();
+    for x in current(conn, favourite)? {
+        let unique_id = format!("{id}_{}", x.fuel.as_str()).to_lowercase();
+        messages.push(Message {
+            topic: format!("{}/sensor/{unique_id}/config", config.discovery_prefix),
+            payload: json!({
+                "name": x.fuel.as_str(),
+                "unique_id": unique_id,
+                "state_topic": format!("{}/{}", topic(config, state, favourite.station), x.fuel.as_str()),
+                "value_template": "{{ value_json.price }}",
+                "unit_of_measurement": "c/L",
+                "icon": "mdi:gas-station",
+                "device": device,
+            })
+            .to_string(),
+        });
+    }
+    Ok(messages)
+}
+
+fn current(conn: &Connection, favourite: &Favourite) -> Result<Vec<BoardPrice>> {
+    let mut select = conn.prepare(
+        "select price.fuel, price.price, max(changed_at) from price
+        join price_history using (state, station, fuel)
+        where price.state = ? and price.station = ?
+        group by price.fuel",
+    )?;
+    let rows = select.query_map((favourite.state as u8, favourite.station), |row| {
+        Ok(BoardPrice {
+            state: favourite.state,
+            station: favourite.station,
+            fuel: row.get(0)?,
+            price: row.get(1)?,
+            changed_at: row.get(2)?,
+        })
+    })?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            host = "localhost"
+
+            [[favourite]]
+            state = "NSW"
+            station = 1
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn discovery_without_brand() {
+        let conn = db::open_in_memory().unwrap();
+        conn.execute_batch(
+            "insert into station (state, id, brand, latitude, longitude, updated_at)
+            values (0, 1, null, -33.87, 151.21, 100);
+            insert into price (state, station, fuel, updated_at, price) values (0, 1, 5, 100, 180.0);
+            insert into price_history (state, station, fuel, changed_at, price) values (0, 1, 5, 100, 180.0);",
+        )
+        .unwrap();
+        let config = config();
+
+        let messages = discovery(&conn, &config, &config.favourites[0]).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0].topic,
+            "homeassistant/sensor/fuel_nsw_1_unleaded91/config"
+        );
+        let payload: serde_json::Value = serde_json::from_str(&messages[0].payload).unwrap();
+        assert_eq!(payload["device"]["name"], "NSW 1");
+        assert_eq!(payload["device"]["manufacturer"], "NSW 1");
+        assert_eq!(payload["state_topic"], "fuel/NSW/1/Unleaded91");
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use geo::{Distance, Haversine, Point};
+use rstar::{primitives::GeomWithData, RTree, AABB};
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::{Fuel, State};
+
+// kilometres per degree of latitude, close enough for building a search box
+const KM_PER_DEGREE: f64 = 111.32;
+
+/// Stations and their current prices, indexed by location.
+pub struct StationIndex {
+    // points are (longitude, latitude), the value is an index into `stations`
+    tree: RTree<GeomWithData<[f64; 2], usize>>,
+    stations: Vec<IndexedStation>,
+}
+
+pub struct IndexedStation {
+    pub state: State,
+    pub id: u32,
+    pub brand: Option<String>,
+    // (longitude, latitude)
+    pub point: Point,
+    prices: HashMap<u8, (f64, u64)>,
+}
+
+impl IndexedStation {
+    /// The current price and when it was updated, if `fuel` is available.
+    pub fn price(&self, fuel: Fuel) -> Option<(f64, u64)> {
+        self.prices.get(&(fuel as u8)).copied()
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct Nearby {
+    pub state: State,
+    pub station: u32,
+    pub brand: Option<String>,
+    pub latitude: f64,
+    pub longitude: f64,
+    // kilometres
+    pub distance: f64,
+    // cents per liter
+    pub price: f64,
+    pub updated_at: u64,
+}
+
+impl StationIndex {
+    /// Loads every station with a location and its available prices.
+    pub fn load(conn: &Connection) -> Result<Self> {
+        let mut stations = Vec::new();
+        let mut ids = HashMap::new();
+        let mut select =
+            conn.prepare("select state, id, brand, latitude, longitude from station")?;
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            let state: State = row.get(0)?;
+            let id: u32 = row.get(1)?;
+            ids.insert((state as u8, id), stations.len());
+            stations.push(IndexedStation {
+                state,
+                id,
+                brand: row.get(2)?,
+                point: Point::new(row.get(4)?, row.get(3)?),
+                prices: HashMap::new(),
+            });
+        }
+
+        let mut select = conn.prepare(
+            "select state, station, fuel, price, updated_at from current_price where price is not null",
+        )?;
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            let key: (u8, u32) = (row.get(0)?, row.get(1)?);
+            if let Some(i) = ids.get(&key) {
+                stations[*i]
+                    .prices
+                    .insert(row.get(2)?, (row.get(3)?, row.get(4)?));
+            }
+        }
+
+        let tree = RTree::bulk_load(
+            stations
+                .iter()
+                .enumerate()
+                .map(|(i, x)| GeomWithData::new([x.point.x(), x.point.y()], i))
+                .collect(),
+        );
+        Ok(Self { tree, stations })
+    }
+
+    /// Stations selling `fuel` within `radius` km of `origin` (longitude, latitude),
+    /// cheapest first.
+    ///
+    /// `detour_cost` is what a kilometre of driving is worth in cents per liter,
+    /// the round trip to each station is added to its price when ranking. With
+    /// the default of 0 stations are ranked by price, then distance.
+    pub fn nearest(
+        &self,
+        origin: Point,
+        fuel: Fuel,
+        radius: f64,
+        detour_cost: f64,
+        limit: usize,
+    ) -> Vec<Nearby> {
+        let mut nearby = Vec::new();
+        for station in self.within(&search_box(origin, radius)) {
+            let Some((price, updated_at)) = station.price(fuel) else {
+                continue;
+            };
+            let distance = Haversine.distance(origin, station.point) / 1000.0;
+            if distance > radius {
+                continue;
+            }
+            nearby.push(Nearby {
+                state: station.state,
+                station: station.id,
+                brand: station.brand.clone(),
+                latitude: station.point.y(),
+                longitude: station.point.x(),
+                distance,
+                price,
+                updated_at,
+            });
+        }
+
+        let score = |x: &Nearby| x.price + detour_cost * 2.0 * x.distance;
+        nearby.sort_by(|a, b| {
+            score(a)
+                .total_cmp(&score(b))
+                .then(a.distance.total_cmp(&b.distance))
+        });
+        nearby.truncate(limit);
+        nearby
+    }
+
+    /// Stations inside a box of (longitude, latitude) corners.
+    pub fn within<'a>(
+        &'a self,
+        envelope: &AABB<[f64; 2]>,
+    ) -> impl Iterator<Item = &'a IndexedStation> + 'a {
+        self.tree
+            .locate_in_envelope(envelope)
+            .map(|x| &self.stations[x.data])
+    }
+}
+
+/// A box around `origin` that contains everything within `radius` km.
+fn search_box(origin: Point, radius: f64) -> AABB<[f64; 2]> {
+    let lat = radius / KM_PER_DEGREE;
+    // widens towards the poles, clamped so it doesn't blow up near them
+    let lon = radius / (KM_PER_DEGREE * origin.y().to_radians().cos().max(0.01));
+    AABB::from_corners(
+        [origin.x() - lon, origin.y() - lat],
+        [origin.x() + lon, origin.y() + lat],
+    )
+}
+
+/// Loads the index and runs a single query, see [`StationIndex::nearest`].
+pub fn nearest(
+    conn: &Connection,
+    origin: Point,
+    fuel: Fuel,
+    radius: f64,
+    detour_cost: f64,
+    limit: usize,
+) -> Result<Vec<Nearby>> {
+    Ok(StationIndex::load(conn)?.nearest(origin, fuel, radius, detour_cost, limit))
+}
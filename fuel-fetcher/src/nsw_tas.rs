@@ -1,8 +1,8 @@
-use anyhow::{bail, Result};
+use anyhow::Result;
 use geo::Point;
 use serde::{Deserialize, Serialize};
 
-use crate::{CurrentPrice, Fuel, State, Station};
+use crate::{fuels::Codes, CurrentPrice, State, Station};
 
 fn data(state: State) -> Result<RawData> {
     let agent = crate::agent();
@@ -23,20 +23,11 @@ fn data(state: State) -> Result<RawData> {
     Ok(data)
 }
 
-pub fn prices(state: State) -> Result<Vec<CurrentPrice>> {
+pub fn prices(state: State, codes: &Codes) -> Result<Vec<CurrentPrice>> {
     let mut prices = Vec::new();
     for raw in data(state)?.prices {
-        let fuel = match &*raw.fueltype {
-            "B20" | "EV" => continue,
-            "DL" => Fuel::Diesel,
-            "E10" => Fuel::Ethanol10,
-            "E85" => Fuel::Ethanol85,
-            "LPG" => Fuel::LPG,
-            "P95" => Fuel::Unleaded95,
-            "P98" => Fuel::Unleaded98,
-            "PDL" => Fuel::PremiumDiesel,
-            "U91" => Fuel::Unleaded91,
-            x => bail!("unknown fuel {x}"),
+        let Some(fuel) = codes.get(&raw.fueltype)? else {
+            continue;
         };
         prices.push(CurrentPrice {
             state,
@@ -55,7 +46,8 @@ pub fn stations(state: State) -> Result<Vec<Station>> {
         stations.push(Station {
             state,
             id: raw.code.parse()?,
-            point: Point::new(raw.location.latitude, raw.location.longitude),
+            brand: None,
+            point: Point::new(raw.location.longitude, raw.location.latitude),
         })
     }
     Ok(stations)
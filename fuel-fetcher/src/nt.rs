@@ -1,41 +1,96 @@
 use anyhow::{bail, Context, Result};
 use geo::Point;
-use scraper::{Html, Selector};
+use scraper::{ElementRef, Html, Selector};
 use serde::Deserialize;
 
-use crate::{CurrentPrice, Fuel, State, Station};
+use crate::{fuels::Codes, CurrentPrice, State, Station};
 
-fn data() -> Result<RawData> {
-    // all data is returned regardless of params, only seem to be used by the client
-    let body = crate::agent().get("https://myfuelnt.nt.gov.au/Home/Results?searchOptions=region&Suburb=&SuburbId=0&RegionId=1&FuelCode=DL&BrandIdentifier=").call()?.into_string()?;
-    let html = Html::parse_document(&body);
-    for x in html.select(&Selector::parse("#serverJson").expect("hardcoded")) {
-        let json = x.attr("value").context("json missing")?;
-        return Ok(serde_json::from_str(json)?);
+// all data is returned regardless of params, only seem to be used by the client
+const URL: &str = "https://myfuelnt.nt.gov.au/Home/Results?searchOptions=region&Suburb=&SuburbId=0&RegionId=1&FuelCode=DL&BrandIdentifier=";
+
+fn data(url: Option<&str>) -> Result<RawData> {
+    let body = crate::agent()
+        .get(url.unwrap_or(URL))
+        .call()?
+        .into_string()?;
+    parse(&body)
+}
+
+/// Parses either the results page or the same data served directly as json.
+fn parse(body: &str) -> Result<RawData> {
+    if body.trim_start().starts_with('{') {
+        return serde_json::from_str(body).context("invalid json response");
+    }
+
+    let html = Html::parse_document(body);
+    let selector = Selector::parse("#serverJson").expect("hardcoded");
+    let matches: Vec<ElementRef> = html.select(&selector).collect();
+    let element = match &*matches {
+        [x] => x,
+        [] => bail!("failed to find json ({})", describe(&html)),
+        x => bail!(
+            "found {} #serverJson elements ({})",
+            x.len(),
+            describe(&html)
+        ),
+    };
+
+    let tag = element.value();
+    if tag.name() != "input" || tag.attr("type") != Some("hidden") {
+        bail!(
+            "expected #serverJson to be a hidden input, found <{} type={:?}> ({})",
+            tag.name(),
+            tag.attr("type"),
+            describe(&html)
+        );
     }
+    let json = tag
+        .attr("value")
+        .with_context(|| format!("#serverJson has no value ({})", describe(&html)))?;
+    serde_json::from_str(json).with_context(|| {
+        format!(
+            "invalid json in #serverJson, {} bytes ({})",
+            json.len(),
+            describe(&html)
+        )
+    })
+}
+
+/// A short summary of the page, to help work out what changed when it can't be parsed.
+fn describe(html: &Html) -> String {
+    let title = Selector::parse("title").expect("hardcoded");
+    let title = html
+        .select(&title)
+        .next()
+        .map(|x| x.text().collect::<String>().trim().to_string())
+        .unwrap_or_default();
+
+    let hidden = Selector::parse("input[type=hidden]").expect("hardcoded");
+    let hidden: Vec<String> = html
+        .select(&hidden)
+        .map(|x| {
+            let x = x.value();
+            let name = x.id().or(x.attr("name")).unwrap_or("?");
+            let len = x.attr("value").map_or(0, str::len);
+            format!("{name} ({len} bytes)")
+        })
+        .collect();
 
-    bail!("failed to find json")
+    format!("title {title:?}, hidden inputs [{}]", hidden.join(", "))
 }
 
-pub fn prices() -> Result<Vec<CurrentPrice>> {
+pub fn prices(codes: &Codes, url: Option<&str>) -> Result<Vec<CurrentPrice>> {
+    to_prices(data(url)?, codes)
+}
+
+fn to_prices(data: RawData, codes: &Codes) -> Result<Vec<CurrentPrice>> {
     let mut prices = Vec::new();
-    for station in data()?.fuel_outlet {
+    for station in data.fuel_outlet {
         for raw in station.available_fuels {
-            let fuel = match &*raw.fuel_code {
-                "E85" => Fuel::Ethanol85,
-                "LPG" => Fuel::LPG,
-                "PD" => Fuel::PremiumDiesel,
-                "P98" => Fuel::Unleaded98,
-                "P95" => Fuel::Unleaded95,
-                "U91" | "LAF" => Fuel::Unleaded91,
-                "DL" => Fuel::Diesel,
-                x => bail!("unknown fuel code: {x}"),
-            };
-            let price = if raw.is_available {
-                Some(raw.price)
-            } else {
-                None
+            let Some(fuel) = codes.get(&raw.fuel_code)? else {
+                continue;
             };
+            let price = if raw.is_available { raw.price } else { None };
             prices.push(CurrentPrice {
                 state: State::NT,
                 station: station.fuel_outlet_id,
@@ -45,19 +100,28 @@ pub fn prices() -> Result<Vec<CurrentPrice>> {
         }
     }
 
-    return Ok(prices);
+    Ok(prices)
+}
+
+pub fn stations(url: Option<&str>) -> Result<Vec<Station>> {
+    to_stations(data(url)?)
 }
 
-pub fn stations() -> Result<Vec<Station>> {
+fn to_stations(data: RawData) -> Result<Vec<Station>> {
     let mut stations = Vec::new();
-    for station in data()?.fuel_outlet {
+    for station in data.fuel_outlet {
+        let (Some(latitude), Some(longitude)) = (station.latitude, station.longitude) else {
+            eprintln!("NT station {} has no location", station.fuel_outlet_id);
+            continue;
+        };
         stations.push(Station {
             state: State::NT,
             id: station.fuel_outlet_id,
-            point: Point::new(station.latitude, station.longitude),
+            brand: None,
+            point: Point::new(longitude, latitude),
         })
     }
-    return Ok(stations);
+    Ok(stations)
 }
 
 #[derive(Deserialize)]
@@ -69,17 +133,112 @@ struct RawData {
 #[derive(Deserialize)]
 #[serde(rename_all = "PascalCase")]
 struct RawStation {
+    #[serde(default)]
     available_fuels: Vec<RawFuel>,
     fuel_outlet_id: u32,
-    longitude: f64,
-    latitude: f64,
+    longitude: Option<f64>,
+    latitude: Option<f64>,
 }
 
 #[derive(Deserialize)]
 #[serde(rename_all = "PascalCase")]
 struct RawFuel {
     fuel_code: String,
-    price: f64,
+    // sometimes missing for unavailable fuels
+    price: Option<f64>,
     #[serde(rename = "isAvailable")]
     is_available: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Fuel, FuelCodes};
+
+    fn fixture(name: &str) -> String {
+        let path = format!("{}/fixtures/nt/{name}", env!("CARGO_MANIFEST_DIR"));
+        std::fs::read_to_string(&path).expect(&path)
+    }
+
+    fn prices(name: &str) -> Result<Vec<CurrentPrice>> {
+        let codes = FuelCodes::load(None)?;
+        to_prices(parse(&fixture(name))?, &codes.nt)
+    }
+
+    #[test]
+    fn results_page() {
+        let prices = prices("results.html").unwrap();
+        assert_eq!(prices.len(), 5);
+
+        let find = |station, fuel: Fuel| {
+            prices
+                .iter()
+                .find(|x| x.station == station && x.fuel as u8 == fuel as u8)
+                .unwrap()
+                .price
+        };
+        assert_eq!(find(101, Fuel::Diesel), Some(199.9));
+        // LAF is sold as 91
+        assert_eq!(find(101, Fuel::Unleaded91), Some(189.9));
+        // unavailable with a placeholder price
+        assert_eq!(find(101, Fuel::Unleaded98), None);
+        // unavailable without a price at all
+        assert_eq!(find(102, Fuel::LPG), None);
+    }
+
+    #[test]
+    fn json_response() {
+        let prices = prices("results.json").unwrap();
+        assert_eq!(prices.len(), 1);
+        assert_eq!(prices[0].price, Some(201.5));
+    }
+
+    #[test]
+    fn missing_location() {
+        let data = parse(&fixture("results.html")).unwrap();
+        let stations = to_stations(data).unwrap();
+        assert_eq!(stations.len(), 1);
+        assert_eq!(stations[0].id, 101);
+    }
+
+    #[test]
+    fn missing_station_id() {
+        let e = prices("missing_id.html").unwrap_err();
+        assert!(
+            format!("{e:#}").contains("missing field `FuelOutletId`"),
+            "{e:#}"
+        );
+    }
+
+    #[test]
+    fn no_json() {
+        let e = prices("no_json.html").unwrap_err().to_string();
+        assert!(e.contains("failed to find json"), "{e}");
+        assert!(e.contains("title \"MyFuel NT - Maintenance\""), "{e}");
+        assert!(e.contains("__RequestVerificationToken (5 bytes)"), "{e}");
+    }
+
+    #[test]
+    fn duplicate_json() {
+        let e = prices("duplicate.html").unwrap_err().to_string();
+        assert!(e.contains("found 2 #serverJson elements"), "{e}");
+    }
+
+    #[test]
+    fn malformed_json() {
+        let e = prices("malformed.html").unwrap_err().to_string();
+        assert!(e.contains("invalid json in #serverJson"), "{e}");
+    }
+
+    #[test]
+    fn not_an_input() {
+        let e = prices("not_input.html").unwrap_err().to_string();
+        assert!(e.contains("found <div type=None>"), "{e}");
+    }
+
+    #[test]
+    fn unknown_fuel() {
+        let e = prices("unknown_fuel.html").unwrap_err().to_string();
+        assert!(e.contains("unknown fuel code: H2"), "{e}");
+    }
+}
@@ -0,0 +1,74 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Months, NaiveDate};
+use rusqlite::Connection;
+
+use crate::{
+    partition::{self, Row},
+    State,
+};
+
+// fuel-history writes history.parquet next to it
+const FILE: &str = "fetcher.parquet";
+
+/// Writes `price_history` under `dir` as `state=NSW/month=2024-03/fetcher.parquet`,
+/// with UTC months. Partitions already written after their month ended are
+/// skipped, so only new ones (and the current month) are written again.
+/// Returns the partitions that were written.
+pub fn export(conn: &Connection, dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut written = Vec::new();
+    for state in State::all() {
+        let range: Option<(u64, u64)> = conn.query_row(
+            "select min(changed_at), max(changed_at) from price_history where state = ?",
+            [state as u8],
+            |row| Ok(row.get::<_, Option<u64>>(0)?.zip(row.get(1)?)),
+        )?;
+        let Some((first, last)) = range else {
+            continue;
+        };
+
+        let mut month = month_start(first);
+        while timestamp(month) <= last {
+            let next = month + Months::new(1);
+            let path = partition::path(dir, state, month, FILE);
+            if !partition::is_complete(&path, month)? {
+                let rows = select(conn, state, timestamp(month), timestamp(next))?;
+                if !rows.is_empty() {
+                    partition::write(&path, state, &rows)?;
+                    written.push(path);
+                }
+            }
+            month = next;
+        }
+    }
+    Ok(written)
+}
+
+fn select(conn: &Connection, state: State, from: u64, to: u64) -> Result<Vec<Row>> {
+    let mut select = conn.prepare(
+        "select station, fuel, changed_at, price from price_history
+        where state = ? and changed_at >= ? and changed_at < ?
+        order by changed_at, station, fuel",
+    )?;
+    let rows = select.query_map((state as u8, from, to), |row| {
+        Ok(Row {
+            timestamp: DateTime::from_timestamp(row.get(2)?, 0).expect("timestamp in range"),
+            station: Some(row.get(0)?),
+            fuel: row.get(1)?,
+            price: row.get(3)?,
+        })
+    })?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+fn month_start(timestamp: u64) -> NaiveDate {
+    let date = DateTime::from_timestamp(timestamp as i64, 0)
+        .expect("timestamp in range")
+        .date_naive();
+    date.with_day(1).expect("every month has a first")
+}
+
+fn timestamp(date: NaiveDate) -> u64 {
+    date.and_time(Default::default()).and_utc().timestamp() as u64
+}
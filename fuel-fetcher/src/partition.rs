@@ -0,0 +1,126 @@
+//! Parquet partitions of price history, `state=NSW/month=2024-03/<file>`. Also
+//! used by fuel-history, so both write the same schema next to each other, each
+//! to its own file.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Months, NaiveDate, Utc};
+use parquet::{
+    basic::{Compression, ZstdLevel},
+    data_type::{ByteArray, ByteArrayType, Int32Type, Int64Type},
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::parser::parse_message_type,
+};
+
+use crate::{Fuel, State};
+
+const SCHEMA: &str = "
+message price_history {
+    required int64 timestamp (TIMESTAMP(MILLIS, true));
+    required binary state (STRING);
+    optional int64 station;
+    required binary fuel (STRING);
+    optional int32 price (DECIMAL(9, 2));
+}";
+
+pub struct Row {
+    pub timestamp: DateTime<Utc>,
+    pub station: Option<u64>,
+    pub fuel: Fuel,
+    // cents per liter
+    pub price: Option<f64>,
+}
+
+/// The partition of `state`'s UTC `month` under `dir`, `file` being the
+/// producer's own so neither overwrites the other's.
+pub fn path(dir: &Path, state: State, month: NaiveDate, file: &str) -> PathBuf {
+    dir.join(format!("state={}", state.as_str()))
+        .join(format!("month={}", month.format("%Y-%m")))
+        .join(file)
+}
+
+/// Whether `path` was written after `month` ended, when everything in it had
+/// happened.
+pub fn is_complete(path: &Path, month: NaiveDate) -> Result<bool> {
+    let end = (month + Months::new(1))
+        .and_time(Default::default())
+        .and_utc()
+        .timestamp() as u64;
+    match fs::metadata(path) {
+        Ok(x) => Ok(x.modified()? >= UNIX_EPOCH + Duration::from_secs(end)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn write(path: &Path, state: State, rows: &[Row]) -> Result<()> {
+    let props = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .build();
+    fs::create_dir_all(path.parent().expect("partition has a parent"))?;
+    // written next to it first so a failed export doesn't leave half a file
+    let tmp = path.with_extension("parquet.tmp");
+    let mut writer = SerializedFileWriter::new(
+        fs::File::create(&tmp)?,
+        Arc::new(parse_message_type(SCHEMA)?),
+        Arc::new(props),
+    )?;
+
+    let mut group = writer.next_row_group()?;
+    let timestamps: Vec<i64> = rows
+        .iter()
+        .map(|x| x.timestamp.timestamp_millis())
+        .collect();
+    let states: Vec<ByteArray> = rows.iter().map(|_| state.as_str().into()).collect();
+    let stations: Vec<i64> = rows
+        .iter()
+        .filter_map(|x| x.station)
+        .map(|x| x as i64)
+        .collect();
+    // 1 where the station id is set, 0 where it's null
+    let stations_defined: Vec<i16> = rows.iter().map(|x| x.station.is_some().into()).collect();
+    let fuels: Vec<ByteArray> = rows.iter().map(|x| x.fuel.as_str().into()).collect();
+    let prices: Vec<i32> = rows
+        .iter()
+        .filter_map(|x| x.price)
+        .map(|x| (x * 100.0).round() as i32)
+        .collect();
+    let prices_defined: Vec<i16> = rows.iter().map(|x| x.price.is_some().into()).collect();
+
+    let mut column = group.next_column()?.expect("timestamp column");
+    column
+        .typed::<Int64Type>()
+        .write_batch(&timestamps, None, None)?;
+    column.close()?;
+    let mut column = group.next_column()?.expect("state column");
+    column
+        .typed::<ByteArrayType>()
+        .write_batch(&states, None, None)?;
+    column.close()?;
+    let mut column = group.next_column()?.expect("station column");
+    column
+        .typed::<Int64Type>()
+        .write_batch(&stations, Some(&stations_defined), None)?;
+    column.close()?;
+    let mut column = group.next_column()?.expect("fuel column");
+    column
+        .typed::<ByteArrayType>()
+        .write_batch(&fuels, None, None)?;
+    column.close()?;
+    let mut column = group.next_column()?.expect("price column");
+    column
+        .typed::<Int32Type>()
+        .write_batch(&prices, Some(&prices_defined), None)?;
+    column.close()?;
+
+    group.close()?;
+    writer.close()?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
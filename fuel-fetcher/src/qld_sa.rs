@@ -1,35 +1,62 @@
-use anyhow::{bail, Result};
+use std::collections::HashMap;
+
+use anyhow::Result;
 use geo::Point;
+use rusqlite::{Connection, OptionalExtension};
 use serde::Deserialize;
 
-use crate::{CurrentPrice, Fuel, State, Station};
+use crate::{fuels::Codes, CurrentPrice, Fuel, State, Station};
 
-pub fn prices(state: State, token: &str) -> Result<Vec<CurrentPrice>> {
-    let auth = format!("fpdapi subscribertoken={token}");
-    let url = match state {
-        State::QLD =>"https://fppdirectapi-prod.fuelpricesqld.com.au/Price/GetSitesPrices?countryId=21&geoRegionLevel=3&geoRegionId=1",
-        State::SA => "https://fppdirectapi-prod.safuelpricinginformation.com.au/Price/GetSitesPrices?countryId=21&geoRegionLevel=3&geoRegionId=4",
-        _ => panic!("unexpected state {state:?}")
-    };
+/// How long the reference data is cached for before being fetched again.
+const REFERENCE_TTL: u64 = 24 * 60 * 60;
+
+fn base_url(state: State) -> &'static str {
+    match state {
+        State::QLD => "https://fppdirectapi-prod.fuelpricesqld.com.au",
+        State::SA => "https://fppdirectapi-prod.safuelpricinginformation.com.au",
+        _ => panic!("unexpected state {state:?}"),
+    }
+}
 
-    let response: Prices = crate::agent()
-        .get(url)
-        .set("authorization", &auth)
+fn get<T: serde::de::DeserializeOwned>(state: State, token: &str, path: &str) -> Result<T> {
+    Ok(crate::agent()
+        .get(&format!("{}{path}", base_url(state)))
+        .set("authorization", &format!("fpdapi subscribertoken={token}"))
         .call()?
-        .into_json()?;
+        .into_json()?)
+}
+
+pub fn prices(
+    state: State,
+    token: &str,
+    codes: &Codes,
+    conn: &Connection,
+) -> Result<Vec<CurrentPrice>> {
+    let path = match state {
+        State::QLD => "/Price/GetSitesPrices?countryId=21&geoRegionLevel=3&geoRegionId=1",
+        State::SA => "/Price/GetSitesPrices?countryId=21&geoRegionLevel=3&geoRegionId=4",
+        _ => panic!("unexpected state {state:?}"),
+    };
+    let response: Prices = get(state, token, path)?;
+
+    // only needed for fuel ids missing from fuels.toml, loaded the first time one is seen
+    let mut reference: Option<Reference> = None;
+    // fuel ids missing from fuels.toml to what their names map to
+    let mut detected: HashMap<u32, Option<Fuel>> = HashMap::new();
     let mut prices = Vec::new();
     for raw in response.site_prices {
-        let fuel = match raw.fuel_id {
-            2 => Fuel::Unleaded91,
-            3 => Fuel::Diesel,
-            4 => Fuel::LPG,
-            5 => Fuel::Unleaded95,
-            8 => Fuel::Unleaded98,
-            12 => Fuel::Ethanol10,
-            14 => Fuel::PremiumDiesel,
-            19 => Fuel::Ethanol85,
-            21 => Fuel::Unleaded91, // https://en.wikipedia.org/wiki/Opal_(fuel)
-            x => bail!("unknown fuel type: {x}"),
+        let code = raw.fuel_id.to_string();
+        let fuel = if codes.contains(&code) {
+            codes.get(&code)?
+        } else {
+            *detected.entry(raw.fuel_id).or_insert_with(|| {
+                let reference =
+                    reference.get_or_insert_with(|| Reference::lenient(state, token, conn));
+                detect(state, raw.fuel_id, reference)
+            })
+        };
+        let Some(fuel) = fuel else {
+            continue;
         };
         let price = match raw.price {
             9999.0 => None,
@@ -59,25 +86,23 @@ struct Price {
     price: f64,
 }
 
-pub fn stations(state: State, token: &str) -> Result<Vec<Station>> {
-    let auth = format!("fpdapi subscribertoken={token}");
-    let url = match state {
-        State::QLD => "https://fppdirectapi-prod.fuelpricesqld.com.au/Subscriber/GetFullSiteDetails?countryId=21&geoRegionLevel=3&geoRegionId=1",
-        State::SA => "https://fppdirectapi-prod.safuelpricinginformation.com.au/Subscriber/GetFullSiteDetails?countryId=21&geoRegionLevel=3&geoRegionId=4",
-        _ => panic!("unexpected state {state:?}")
+pub fn stations(state: State, token: &str, conn: &Connection) -> Result<Vec<Station>> {
+    let path = match state {
+        State::QLD => "/Subscriber/GetFullSiteDetails?countryId=21&geoRegionLevel=3&geoRegionId=1",
+        State::SA => "/Subscriber/GetFullSiteDetails?countryId=21&geoRegionLevel=3&geoRegionId=4",
+        _ => panic!("unexpected state {state:?}"),
     };
-    let response: Sites = crate::agent()
-        .get(url)
-        .set("authorization", &auth)
-        .call()?
-        .into_json()?;
+    let response: Sites = get(state, token, path)?;
 
+    // always refresh, brands change more often than fuel types
+    let reference = Reference::fetch(state, token, conn)?;
     let mut stations = Vec::new();
     for site in response.sites {
         stations.push(Station {
             state,
             id: site.id,
-            point: Point::new(site.lat, site.lng),
+            brand: reference.brands.get(&site.brand_id).cloned(),
+            point: Point::new(site.lng, site.lat),
         })
     }
     Ok(stations)
@@ -94,6 +119,221 @@ struct Sites {
 struct Site {
     #[serde(rename = "S")]
     id: u32,
+    #[serde(rename = "B")]
+    brand_id: u32,
     lat: f64,
     lng: f64,
 }
+
+/// Maps a fuel id missing from fuels.toml by its name in the reference data,
+/// `None` if it isn't one we track.
+fn detect(state: State, fuel_id: u32, reference: &Reference) -> Option<Fuel> {
+    let Some(name) = reference.fuels.get(&fuel_id) else {
+        eprintln!("{} has an unknown fuel type {fuel_id}", state.as_str());
+        return None;
+    };
+    let fuel = fuel_from_name(name);
+    eprintln!(
+        "{} has a new fuel type {fuel_id} ({name}), using it as {}, add it to fuels.toml",
+        state.as_str(),
+        fuel.map_or("ignore", |x| x.as_str())
+    );
+    fuel
+}
+
+/// What an FPD fuel type name like `Premium Unleaded 95` is.
+fn fuel_from_name(name: &str) -> Option<Fuel> {
+    let name: String = name
+        .chars()
+        .filter(|x| x.is_ascii_alphanumeric())
+        .map(|x| x.to_ascii_lowercase())
+        .collect();
+    Some(match &*name {
+        "unleaded" | "unleaded91" | "ulp" => Fuel::Unleaded91,
+        "premiumunleaded95" | "unleaded95" | "pulp95" => Fuel::Unleaded95,
+        "premiumunleaded98" | "unleaded98" | "pulp98" => Fuel::Unleaded98,
+        "diesel" => Fuel::Diesel,
+        "premiumdiesel" => Fuel::PremiumDiesel,
+        "lpg" | "autogas" => Fuel::LPG,
+        "e10" => Fuel::Ethanol10,
+        "e85" => Fuel::Ethanol85,
+        _ => return None,
+    })
+}
+
+/// Fuel types, brands and geographic regions, cached in the `fpd_*` tables.
+pub struct Reference {
+    pub fuels: HashMap<u32, String>,
+    pub brands: HashMap<u32, String>,
+}
+
+impl Reference {
+    /// Loads the reference data from the database, fetching it if it's missing
+    /// or stale.
+    pub fn cached(state: State, token: &str, conn: &Connection) -> Result<Self> {
+        let fetched_at: Option<u64> = conn
+            .query_row(
+                "select fetched_at from fpd_reference where state = ?",
+                [state as u8],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match fetched_at {
+            Some(x) if x + REFERENCE_TTL > crate::now() => Self::load(state, conn),
+            _ => Self::fetch(state, token, conn),
+        }
+    }
+
+    /// Like [`Self::cached`], but falls back to the cached copy however old it
+    /// is, or nothing, so prices can still be fetched when the reference
+    /// endpoints are down.
+    fn lenient(state: State, token: &str, conn: &Connection) -> Self {
+        Self::cached(state, token, conn).unwrap_or_else(|e| {
+            eprintln!("{} reference data failed: {e:#}", state.as_str());
+            Self::load(state, conn).unwrap_or_else(|e| {
+                eprintln!("{} cached reference data failed: {e:#}", state.as_str());
+                Self {
+                    fuels: HashMap::new(),
+                    brands: HashMap::new(),
+                }
+            })
+        })
+    }
+
+    /// Fetches the reference data and replaces the cached copy.
+    pub fn fetch(state: State, token: &str, conn: &Connection) -> Result<Self> {
+        let fuels: FuelTypes = get(state, token, "/Subscriber/GetCountryFuelTypes?countryId=21")?;
+        let brands: Brands = get(state, token, "/Subscriber/GetCountryBrands?countryId=21")?;
+        let regions: Regions = get(
+            state,
+            token,
+            "/Subscriber/GetCountryGeographicRegions?countryId=21",
+        )?;
+
+        let state_id = state as u8;
+        let tx = conn.unchecked_transaction()?;
+        for table in ["fpd_fuel", "fpd_brand", "fpd_region"] {
+            tx.execute(&format!("delete from {table} where state = ?"), [state_id])?;
+        }
+        {
+            let mut insert =
+                tx.prepare("insert into fpd_fuel (state, id, name) values (?, ?, ?)")?;
+            for x in &fuels.fuels {
+                insert.execute((state_id, x.fuel_id, &x.name))?;
+            }
+            let mut insert =
+                tx.prepare("insert into fpd_brand (state, id, name) values (?, ?, ?)")?;
+            for x in &brands.brands {
+                insert.execute((state_id, x.brand_id, &x.name))?;
+            }
+            let mut insert = tx.prepare(
+                "insert into fpd_region (state, level, id, name, abbrev, parent_id) values (?, ?, ?, ?, ?, ?)",
+            )?;
+            for x in &regions.geographic_regions {
+                insert.execute((
+                    state_id,
+                    x.geo_region_level,
+                    x.geo_region_id,
+                    &x.name,
+                    &x.abbrev,
+                    x.geo_region_parent_id,
+                ))?;
+            }
+        }
+        tx.execute(
+            "insert or replace into fpd_reference (state, fetched_at) values (?, ?)",
+            (state_id, crate::now()),
+        )?;
+        tx.commit()?;
+
+        Ok(Self {
+            fuels: fuels
+                .fuels
+                .into_iter()
+                .map(|x| (x.fuel_id, x.name))
+                .collect(),
+            brands: brands
+                .brands
+                .into_iter()
+                .map(|x| (x.brand_id, x.name))
+                .collect(),
+        })
+    }
+
+    fn load(state: State, conn: &Connection) -> Result<Self> {
+        let load = |table: &str| -> Result<HashMap<u32, String>> {
+            let mut select =
+                conn.prepare(&format!("select id, name from {table} where state = ?"))?;
+            let rows = select.query_map([state as u8], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        };
+        Ok(Self {
+            fuels: load("fpd_fuel")?,
+            brands: load("fpd_brand")?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct FuelTypes {
+    fuels: Vec<FuelType>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct FuelType {
+    fuel_id: u32,
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Brands {
+    brands: Vec<Brand>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Brand {
+    brand_id: u32,
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Regions {
+    geographic_regions: Vec<Region>,
+}
+
+// level 1 is a suburb, 2 a city and 3 a state, parents are one level up
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Region {
+    geo_region_level: u32,
+    geo_region_id: u32,
+    name: String,
+    abbrev: Option<String>,
+    geo_region_parent_id: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuel_names() {
+        assert!(matches!(fuel_from_name("Unleaded"), Some(Fuel::Unleaded91)));
+        assert!(matches!(
+            fuel_from_name("Premium Unleaded 95"),
+            Some(Fuel::Unleaded95)
+        ));
+        assert!(matches!(fuel_from_name("e10"), Some(Fuel::Ethanol10)));
+        assert!(matches!(
+            fuel_from_name("Premium Diesel"),
+            Some(Fuel::PremiumDiesel)
+        ));
+        assert!(fuel_from_name("Bio-Diesel 20").is_none());
+        assert!(fuel_from_name("OPAL").is_none());
+    }
+}
@@ -0,0 +1,311 @@
+use anyhow::Result;
+use chrono::DateTime;
+use geo::Point;
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
+
+use crate::{
+    aggregate,
+    cycles::{self, Kind, Turn},
+    nearest::{Nearby, StationIndex},
+    wa, Fuel,
+};
+
+// cents per liter, waiting for less than this isn't worth it
+const MIN_SAVING: f64 = 1.0;
+
+// FuelWatch prices are fixed for 24 hours, so only a missing update could be wrong
+const FORECAST_CONFIDENCE: f64 = 0.95;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    BuyNow,
+    Wait,
+}
+
+impl Action {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::BuyNow => "buy now",
+            Self::Wait => "wait",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Recommendation {
+    pub action: Action,
+    /// The cheapest station nearby right now
+    pub station: Nearby,
+    /// Cents per liter saved by following the recommendation, expected
+    pub saving: f64,
+    /// 0 to 1
+    pub confidence: f64,
+    pub reason: String,
+}
+
+/// Whether to fill up at the cheapest station within `radius` km of `origin`
+/// now or wait, if there is one.
+///
+/// In WA tomorrow's FuelWatch prices are known. Elsewhere the station's prices
+/// at the same point of previous cycles in its region (see [`cycles`]) are
+/// compared to the lowest they got before the next hike: the expected saving is
+/// the median of those, and the confidence is how many cycles it held in.
+pub fn recommend(
+    conn: &Connection,
+    index: &StationIndex,
+    origin: Point,
+    fuel: Fuel,
+    radius: f64,
+) -> Result<Option<Recommendation>> {
+    let nearby = index.nearest(origin, fuel, radius, 0.0, usize::MAX);
+    let Some(cheapest) = nearby.first() else {
+        return Ok(None);
+    };
+
+    let now = crate::now();
+    if let Some(x) = forecast(conn, &nearby, fuel, now)? {
+        return Ok(Some(x));
+    }
+
+    let Some((level, region, turns)) = region_cycles(conn, cheapest, fuel)? else {
+        return Ok(Some(Recommendation {
+            action: Action::BuyNow,
+            station: cheapest.clone(),
+            saving: 0.0,
+            confidence: 0.0,
+            reason: "no price cycles to go by".into(),
+        }));
+    };
+
+    // the same point of previous cycles is as long after the same kind of
+    // turning point as it is now after the latest one
+    let last = turns.last().expect("at least one turning point");
+    let elapsed = now.saturating_sub(aggregate::day_start(cheapest.state, last.date));
+    let mut savings = Vec::new();
+    for (i, anchor) in turns[..turns.len() - 1].iter().enumerate() {
+        if anchor.kind != last.kind {
+            continue;
+        }
+        let Some(trough) = turns[i + 1..].iter().find(|x| x.kind == Kind::Trough) else {
+            continue;
+        };
+        let time = aggregate::day_start(cheapest.state, anchor.date) + elapsed;
+        // the end of the trough's day, the hike is after it
+        let end = aggregate::day_start(cheapest.state, trough.date) + 24 * 60 * 60;
+        if time >= end {
+            // it had already hiked by this point
+            savings.push(0.0);
+            continue;
+        }
+        let Some(then) = price_at(conn, cheapest, fuel, time)? else {
+            continue;
+        };
+        let lowest = lowest(conn, cheapest, fuel, time, end)?.unwrap_or(then);
+        savings.push((then - lowest).max(0.0));
+    }
+    if savings.is_empty() {
+        return Ok(Some(Recommendation {
+            action: Action::BuyNow,
+            station: cheapest.clone(),
+            saving: 0.0,
+            confidence: 0.0,
+            reason: format!("no history for this point of the cycle in {level} {region}"),
+        }));
+    }
+
+    savings.sort_by(f64::total_cmp);
+    let saving = savings[savings.len() / 2];
+    let helped = savings.iter().filter(|x| **x >= MIN_SAVING).count();
+    let share = helped as f64 / savings.len() as f64;
+    let days = elapsed / (24 * 60 * 60);
+    let since = match last.kind {
+        Kind::Trough => "trough",
+        Kind::Peak => "peak",
+    };
+    let (action, saving, confidence) = if saving >= MIN_SAVING {
+        (Action::Wait, saving, share)
+    } else {
+        (Action::BuyNow, 0.0, 1.0 - share)
+    };
+    Ok(Some(Recommendation {
+        action,
+        station: cheapest.clone(),
+        saving,
+        confidence,
+        reason: format!(
+            "{days} days since the last {since} in {level} {region}, waiting saved {MIN_SAVING}c or more in {helped} of {} previous cycles",
+            savings.len(),
+        ),
+    }))
+}
+
+/// Compares the cheapest price now to the cheapest known for tomorrow, for
+/// stations that publish them.
+fn forecast(
+    conn: &Connection,
+    nearby: &[Nearby],
+    fuel: Fuel,
+    now: u64,
+) -> Result<Option<Recommendation>> {
+    let mut select = conn.prepare(
+        "select price from price_forecast
+        where state = ? and station = ? and fuel = ? and effective_date > ?
+        order by effective_date limit 1",
+    )?;
+    // only WA publishes forecasts, for days that start at 6am
+    let today = wa::trading_day(DateTime::from_timestamp(now as i64, 0).expect("now is in range"));
+    let mut tomorrow: Option<(f64, &Nearby)> = None;
+    for x in nearby {
+        let price: Option<f64> = select
+            .query_row((x.state as u8, x.station, fuel as u8, today), |row| {
+                row.get(0)
+            })
+            .optional()?;
+        if let Some(price) = price {
+            if tomorrow.is_none_or(|(best, _)| price < best) {
+                tomorrow = Some((price, x));
+            }
+        }
+    }
+
+    let Some((price, station)) = tomorrow else {
+        return Ok(None);
+    };
+    let cheapest = &nearby[0];
+    let difference = cheapest.price - price;
+    Ok(Some(if difference >= MIN_SAVING {
+        Recommendation {
+            action: Action::Wait,
+            station: cheapest.clone(),
+            saving: difference,
+            confidence: FORECAST_CONFIDENCE,
+            reason: format!(
+                "{} {} will be {price:.1} tomorrow",
+                station.state.as_str(),
+                station.station
+            ),
+        }
+    } else {
+        Recommendation {
+            action: Action::BuyNow,
+            station: cheapest.clone(),
+            saving: (-difference).max(0.0),
+            confidence: FORECAST_CONFIDENCE,
+            reason: format!("the cheapest tomorrow is {price:.1}"),
+        }
+    }))
+}
+
+/// The turning points of the station's SA4 if it has enough cycles, otherwise
+/// its state's.
+fn region_cycles(
+    conn: &Connection,
+    station: &Nearby,
+    fuel: Fuel,
+) -> Result<Option<(String, String, Vec<Turn>)>> {
+    let sa4: Option<String> = conn
+        .query_row(
+            "select sa4 from station where state = ? and id = ?",
+            (station.state as u8, station.station),
+            |row| row.get(0),
+        )
+        .optional()?
+        .flatten();
+    let mut regions = vec![("state", station.state.as_str().to_string())];
+    if let Some(x) = sa4 {
+        regions.insert(0, ("sa4", x));
+    }
+
+    for (level, region) in regions {
+        let turns = cycles::points(conn, station.state, level, &region, fuel)?;
+        let troughs = turns.iter().filter(|x| x.kind == Kind::Trough).count();
+        if troughs >= 2 {
+            return Ok(Some((level.into(), region, turns)));
+        }
+    }
+    Ok(None)
+}
+
+fn price_at(conn: &Connection, station: &Nearby, fuel: Fuel, time: u64) -> Result<Option<f64>> {
+    Ok(conn
+        .query_row(
+            "select price from price_history
+            where state = ? and station = ? and fuel = ? and changed_at <= ?
+            order by changed_at desc limit 1",
+            (station.state as u8, station.station, fuel as u8, time),
+            |row| row.get(0),
+        )
+        .optional()?
+        .flatten())
+}
+
+fn lowest(
+    conn: &Connection,
+    station: &Nearby,
+    fuel: Fuel,
+    from: u64,
+    to: u64,
+) -> Result<Option<f64>> {
+    Ok(conn.query_row(
+        "select min(price) from price_history
+        where state = ? and station = ? and fuel = ? and changed_at > ? and changed_at < ?",
+        (station.state as u8, station.station, fuel as u8, from, to),
+        |row| row.get(0),
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db, State};
+
+    fn nearby(station: u32, price: f64) -> Nearby {
+        Nearby {
+            state: State::WA,
+            station,
+            brand: None,
+            latitude: -31.95,
+            longitude: 115.86,
+            distance: 1.0,
+            price,
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn forecast_before_6am_is_still_tomorrow() {
+        let conn = db::open_in_memory().unwrap();
+        // published on the 10th for the FuelWatch day starting 6am on the 11th
+        conn.execute(
+            "insert into price_forecast (state, station, fuel, effective_date, fetched_at, price)
+            values (?, 1, ?, '2024-03-11', 0, 180.0)",
+            (State::WA as u8, Fuel::Unleaded91 as u8),
+        )
+        .unwrap();
+        let nearby = [nearby(1, 190.0)];
+        let at = |time| DateTime::parse_from_rfc3339(time).unwrap().timestamp() as u64;
+
+        let x = forecast(
+            &conn,
+            &nearby,
+            Fuel::Unleaded91,
+            at("2024-03-11T02:00:00+08:00"),
+        )
+        .unwrap()
+        .unwrap();
+        assert!(matches!(x.action, Action::Wait));
+        assert_eq!(x.saving, 10.0);
+
+        // in effect from 6am, so no longer a forecast
+        let x = forecast(
+            &conn,
+            &nearby,
+            Fuel::Unleaded91,
+            at("2024-03-11T06:00:00+08:00"),
+        )
+        .unwrap();
+        assert!(x.is_none());
+    }
+}
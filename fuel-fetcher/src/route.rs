@@ -0,0 +1,205 @@
+use std::{fs, io::BufReader, path::Path};
+
+use anyhow::{bail, Context, Result};
+use geo::{
+    Closest, Coord, Distance, Geometry, GeometryCollection, Haversine, HaversineClosestPoint, Line,
+    LineString, Point,
+};
+use geojson::GeoJson;
+use rstar::{
+    primitives::{GeomWithData, Rectangle},
+    RTree, AABB,
+};
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::{nearest::StationIndex, Fuel, State};
+
+// mean earth radius in metres
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+#[derive(Serialize)]
+pub struct OnRoute {
+    pub state: State,
+    pub station: u32,
+    pub brand: Option<String>,
+    pub latitude: f64,
+    pub longitude: f64,
+    // kilometres from the start of the route
+    pub along: f64,
+    // kilometres off the route, one way
+    pub detour: f64,
+    // cents per liter
+    pub price: f64,
+    pub updated_at: u64,
+}
+
+/// Reads a route from a GPX file (tracks, then routes) or a GeoJSON file with a
+/// LineString, using the first one found.
+pub fn load(path: &Path) -> Result<LineString> {
+    let gpx = path
+        .extension()
+        .is_some_and(|x| x.eq_ignore_ascii_case("gpx"));
+    let line = if gpx {
+        let gpx = gpx::read(BufReader::new(fs::File::open(path)?))?;
+        let mut lines = gpx.tracks.iter().flat_map(|x| &x.segments);
+        match lines.next() {
+            // join up all the segments of the track
+            Some(first) => first
+                .points
+                .iter()
+                .chain(lines.flat_map(|x| &x.points))
+                .map(|x| x.point())
+                .collect(),
+            None => gpx
+                .routes
+                .first()
+                .context("no tracks or routes")?
+                .linestring(),
+        }
+    } else {
+        let geojson: GeoJson = fs::read_to_string(path)?.parse()?;
+        let geometries: GeometryCollection = (&geojson).try_into()?;
+        geometries
+            .into_iter()
+            .find_map(|x| match x {
+                Geometry::LineString(x) => Some(x),
+                _ => None,
+            })
+            .context("no LineString")?
+    };
+
+    if line.0.len() < 2 {
+        bail!("route needs at least two points");
+    }
+    Ok(line)
+}
+
+/// The cheapest `limit` stations selling `fuel` within `max_detour` km of `route`,
+/// in the order they're passed. Distances are haversine, to the closest point
+/// on the route.
+pub fn along(
+    conn: &Connection,
+    route: &LineString,
+    fuel: Fuel,
+    max_detour: f64,
+    limit: usize,
+) -> Result<Vec<OnRoute>> {
+    let index = StationIndex::load(conn)?;
+    let segments = Segments::new(route, max_detour);
+
+    let mut stations = Vec::new();
+    for station in index.within(&search_box(route.coords(), max_detour)) {
+        let Some((price, updated_at)) = station.price(fuel) else {
+            continue;
+        };
+        let Some((along, detour)) = segments.closest(station.point) else {
+            continue;
+        };
+        if detour > max_detour {
+            continue;
+        }
+        stations.push(OnRoute {
+            state: station.state,
+            station: station.id,
+            brand: station.brand.clone(),
+            latitude: station.point.y(),
+            longitude: station.point.x(),
+            along,
+            detour,
+            price,
+            updated_at,
+        });
+    }
+
+    stations.sort_by(|a, b| a.price.total_cmp(&b.price));
+    stations.truncate(limit);
+    stations.sort_by(|a, b| a.along.total_cmp(&b.along));
+    Ok(stations)
+}
+
+/// A box around (longitude, latitude) coordinates, expanded by `max_detour` km.
+fn search_box<'a>(coords: impl Iterator<Item = &'a Coord>, max_detour: f64) -> AABB<[f64; 2]> {
+    let mut min = [f64::MAX, f64::MAX];
+    let mut max = [f64::MIN, f64::MIN];
+    for c in coords {
+        min = [min[0].min(c.x), min[1].min(c.y)];
+        max = [max[0].max(c.x), max[1].max(c.y)];
+    }
+    let furthest = min[1].abs().max(max[1].abs());
+    let lat = (max_detour * 1000.0 / EARTH_RADIUS).to_degrees();
+    let lon = lat / furthest.to_radians().cos().max(0.01);
+    AABB::from_corners([min[0] - lon, min[1] - lat], [max[0] + lon, max[1] + lat])
+}
+
+/// The route's lines, indexed by the box around each that's within the detour,
+/// so only the ones near a station are measured.
+struct Segments {
+    lines: Vec<Line>,
+    // kilometres from the start of the route to the start of each line
+    starts: Vec<f64>,
+    tree: RTree<GeomWithData<Rectangle<[f64; 2]>, usize>>,
+}
+
+impl Segments {
+    fn new(route: &LineString, max_detour: f64) -> Self {
+        let lines: Vec<Line> = route.lines().collect();
+        let mut starts = Vec::with_capacity(lines.len());
+        let mut total = 0.0;
+        for line in &lines {
+            starts.push(total);
+            total += Haversine.distance(line.start_point(), line.end_point()) / 1000.0;
+        }
+        let tree = RTree::bulk_load(
+            lines
+                .iter()
+                .enumerate()
+                .map(|(i, x)| {
+                    let envelope = search_box([x.start, x.end].iter(), max_detour);
+                    GeomWithData::new(Rectangle::from_aabb(envelope), i)
+                })
+                .collect(),
+        );
+        Self {
+            lines,
+            starts,
+            tree,
+        }
+    }
+
+    /// How far along the route the closest point to `point` is and how far away
+    /// it is, in kilometres. None if it's nowhere near.
+    fn closest(&self, point: Point) -> Option<(f64, f64)> {
+        self.tree
+            .locate_all_at_point(&[point.x(), point.y()])
+            .filter_map(|x| {
+                let line = self.lines[x.data];
+                let closest = match line.haversine_closest_point(&point) {
+                    Closest::Intersection(x) | Closest::SinglePoint(x) => x,
+                    Closest::Indeterminate => return None,
+                };
+                let along =
+                    self.starts[x.data] + Haversine.distance(line.start_point(), closest) / 1000.0;
+                Some((along, Haversine.distance(closest, point) / 1000.0))
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn haversine_distances() {
+        let route = LineString::from(vec![(151.21, -33.8), (151.21, -33.85), (151.21, -34.0)]);
+        let segments = Segments::new(&route, 5.0);
+
+        // 0.01° of longitude west, a tenth of a degree of latitude along
+        let (along, detour) = segments.closest(Point::new(151.2, -33.9)).unwrap();
+        assert!((along - 11.12).abs() < 0.01, "{along}");
+        assert!((detour - 0.923).abs() < 0.001, "{detour}");
+
+        assert!(segments.closest(Point::new(151.0, -33.9)).is_none());
+    }
+}
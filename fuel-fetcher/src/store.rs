@@ -0,0 +1,400 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use rusqlite::Connection;
+
+use crate::{board::BoardPrice, coords, FetchRun, Prices, Station};
+
+/// Stores one source's prices in a transaction, as of when they were fetched.
+/// Returns how many prices changed and everything written to `price_history`.
+///
+/// The source's current prices are diffed in memory so only new and changed
+/// rows are written. Unchanged ones aren't touched at all, when they were last
+/// seen comes from `source_seen` instead, see the `current_price` view.
+pub fn prices(
+    conn: &Connection,
+    run: &FetchRun,
+    mut prices: Prices,
+) -> Result<(usize, Vec<BoardPrice>)> {
+    let now = run.finished_at;
+    let state = run.source as u8;
+    let mut changes = 0;
+    let mut changed = Vec::new();
+    let tx = conn.unchecked_transaction()?;
+    {
+        let run_id = run.record(&tx)?;
+        stations(&tx, &mut prices.stations, now)?;
+
+        // (station, fuel) to (price, whether the source stopped listing it)
+        let mut current: HashMap<(u32, u8), (Option<f64>, bool)> = HashMap::new();
+        {
+            let mut select = tx.prepare(
+                "select station, fuel, price, last_seen is not null from price where state = ?",
+            )?;
+            let mut rows = select.query([state])?;
+            while let Some(row) = rows.next()? {
+                current.insert((row.get(0)?, row.get(1)?), (row.get(2)?, row.get(3)?));
+            }
+        }
+
+        let mut upsert = tx.prepare(
+            "insert into price (state, station, fuel, updated_at, price) values (?1, ?2, ?3, ?4, ?5)
+            on conflict (state, station, fuel) do update set
+                updated_at = ?4, price = ?5, last_seen = null",
+        )?;
+        let mut clear = tx.prepare(
+            "update price set last_seen = null where state = ? and station = ? and fuel = ?",
+        )?;
+        let mut history = tx.prepare(
+            "insert into price_history (state, station, fuel, changed_at, price, run_id) values (?, ?, ?, ?, ?, ?)",
+        )?;
+
+        let mut listed = HashSet::new();
+        for price in prices.current {
+            let key = (price.station, price.fuel as u8);
+            listed.insert(key);
+            match current.insert(key, (price.price, false)) {
+                Some((old, missing)) if old == price.price => {
+                    if missing {
+                        // back in the feed, so last seen comes from the source again
+                        clear.execute((state, key.0, key.1))?;
+                    }
+                    continue;
+                }
+                Some(_) => changes += 1,
+                None => {}
+            }
+            upsert.execute((state, key.0, key.1, now, price.price))?;
+            history.execute((state, key.0, key.1, now, price.price, run_id))?;
+            changed.push(BoardPrice {
+                state: price.state,
+                station: price.station,
+                fuel: price.fuel,
+                price: price.price,
+                changed_at: now,
+            });
+        }
+
+        // prices the source stopped listing were last seen in its previous fetch
+        let mut missing = tx.prepare(
+            "update price set last_seen = coalesce((select seen_at from source_seen where state = ?1), updated_at)
+            where state = ?1 and station = ?2 and fuel = ?3",
+        )?;
+        for (key, (_, was_missing)) in &current {
+            if !was_missing && !listed.contains(key) {
+                missing.execute((state, key.0, key.1))?;
+            }
+        }
+
+        let mut forecast = tx.prepare(
+            "insert or replace into price_forecast (state, station, fuel, effective_date, fetched_at, price) values (?, ?, ?, ?, ?, ?)",
+        )?;
+        for price in prices.forecast {
+            forecast.execute((
+                price.state as u8,
+                price.station,
+                price.fuel as u8,
+                price.date,
+                now,
+                price.price,
+            ))?;
+        }
+
+        tx.execute(
+            "insert into source_seen (state, seen_at) values (?1, ?2)
+            on conflict (state) do update set seen_at = ?2",
+            (state, now),
+        )?;
+        tx.execute(
+            "update fetch_run set changes = ? where id = ?",
+            (changes, run_id),
+        )?;
+    }
+    tx.commit()?;
+    Ok((changes, changed))
+}
+
+/// Upserts stations, fixing and flagging their locations, see [`coords::check`].
+/// Anything derived from the location is cleared if it moved.
+pub fn stations(conn: &Connection, stations: &mut [Station], now: u64) -> Result<()> {
+    let mut upsert = conn.prepare(
+        "insert into station (state, id, brand, latitude, longitude, updated_at, location_flag)
+        values (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+        on conflict (state, id) do update set
+            brand = ?3, latitude = ?4, longitude = ?5, updated_at = ?6, location_flag = ?7,
+            address = iif(latitude = ?4 and longitude = ?5, address, null),
+            locality = iif(latitude = ?4 and longitude = ?5, locality, null),
+            postcode = iif(latitude = ?4 and longitude = ?5, postcode, null),
+            sa2 = iif(latitude = ?4 and longitude = ?5, sa2, null),
+            sa3 = iif(latitude = ?4 and longitude = ?5, sa3, null),
+            sa4 = iif(latitude = ?4 and longitude = ?5, sa4, null),
+            lga = iif(latitude = ?4 and longitude = ?5, lga, null),
+            poa = iif(latitude = ?4 and longitude = ?5, poa, null)",
+    )?;
+    for station in stations {
+        let problem = coords::check(station);
+        if let Some(problem) = problem {
+            crate::report(station, problem);
+        }
+        let (longitude, latitude) = station.point.x_y();
+        upsert.execute((
+            station.state as u8,
+            station.id,
+            &station.brand,
+            latitude,
+            longitude,
+            now,
+            problem.map(|x| x.as_str()),
+        ))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        time::{Instant, SystemTime, UNIX_EPOCH},
+    };
+
+    use rusqlite::OptionalExtension;
+
+    use super::*;
+    use crate::{db, CurrentPrice, Fuel, State};
+
+    struct TempDb(std::path::PathBuf);
+
+    impl TempDb {
+        fn new(name: &str) -> (Self, Connection) {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos();
+            let path = env::temp_dir().join(format!("fuel-{name}-{nanos}.db"));
+            let conn = db::open_path(&path).unwrap();
+            (Self(path), conn)
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn run(time: u64) -> FetchRun {
+        FetchRun {
+            source: State::NSW,
+            started_at: time,
+            finished_at: time,
+            http_attempts: 1,
+            rows_parsed: None,
+            error: None,
+        }
+    }
+
+    fn feed(prices: &[(u32, Fuel, Option<f64>)]) -> Prices {
+        prices
+            .iter()
+            .map(|&(station, fuel, price)| CurrentPrice {
+                state: State::NSW,
+                station,
+                fuel,
+                price,
+            })
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    fn current(conn: &Connection) -> Vec<(u32, Option<f64>, u64)> {
+        let mut select = conn
+            .prepare("select station, price, updated_at from current_price order by station")
+            .unwrap();
+        let rows = select
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap();
+        rows.collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn only_changes_are_written() {
+        let conn = db::open_in_memory().unwrap();
+        let u91 = Fuel::Unleaded91;
+
+        let (changes, changed) = prices(
+            &conn,
+            &run(100),
+            feed(&[(1, u91, Some(180.0)), (2, u91, Some(190.0))]),
+        )
+        .unwrap();
+        assert_eq!((changes, changed.len()), (0, 2));
+
+        // 1 changes, 2 is the same and 3 is new
+        let (changes, changed) = prices(
+            &conn,
+            &run(200),
+            feed(&[(1, u91, Some(185.0)), (2, u91, Some(190.0)), (3, u91, None)]),
+        )
+        .unwrap();
+        assert_eq!((changes, changed.len()), (1, 2));
+        let recorded: (u8, usize) = conn
+            .query_row(
+                "select source, changes from fetch_run order by id desc limit 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(recorded, (State::NSW as u8, 1));
+        let written: Vec<u64> = conn
+            .prepare("select updated_at from price order by station")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(written, [200, 100, 200]);
+        assert_eq!(
+            current(&conn),
+            [(1, Some(185.0), 200), (2, Some(190.0), 200), (3, None, 200)]
+        );
+
+        // 2 stops being listed, so it was last seen in the previous fetch
+        prices(
+            &conn,
+            &run(300),
+            feed(&[(1, u91, Some(185.0)), (3, u91, None)]),
+        )
+        .unwrap();
+        assert_eq!(
+            current(&conn),
+            [(1, Some(185.0), 300), (2, Some(190.0), 200), (3, None, 300)]
+        );
+
+        // and is seen again unchanged, without a change being recorded
+        let (changes, changed) = prices(
+            &conn,
+            &run(400),
+            feed(&[(1, u91, Some(185.0)), (2, u91, Some(190.0)), (3, u91, None)]),
+        )
+        .unwrap();
+        assert_eq!((changes, changed.len()), (0, 0));
+        let updated_at: u64 = conn
+            .query_row(
+                "select updated_at from price where station = 2",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(updated_at, 100);
+        assert_eq!(
+            current(&conn),
+            [(1, Some(185.0), 400), (2, Some(190.0), 400), (3, None, 400)]
+        );
+
+        let history: usize = conn
+            .query_row("select count(*) from price_history", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(history, 4);
+    }
+
+    #[test]
+    fn updated_at_stays_put_when_a_fetch_fails() {
+        let conn = db::open_in_memory().unwrap();
+        let feed = || feed(&[(1, Fuel::Unleaded91, Some(180.0))]);
+        prices(&conn, &run(100), feed()).unwrap();
+        FetchRun {
+            error: Some("timed out".into()),
+            ..run(200)
+        }
+        .record(&conn)
+        .unwrap();
+        assert_eq!(current(&conn), [(1, Some(180.0), 100)]);
+
+        prices(&conn, &run(300), feed()).unwrap();
+        assert_eq!(current(&conn), [(1, Some(180.0), 300)]);
+    }
+
+    /// The old path, a select then an update or insert for every row.
+    fn row_by_row(conn: &Connection, now: u64, prices: Prices) {
+        let tx = conn.unchecked_transaction().unwrap();
+        {
+            let mut select = tx
+                .prepare("select price from price where state = ? and station = ? and fuel = ?")
+                .unwrap();
+            let mut insert = tx
+                .prepare("insert into price (state, station, fuel, updated_at, price) values (?, ?, ?, ?, ?)")
+                .unwrap();
+            let mut history = tx
+                .prepare("insert into price_history (state, station, fuel, changed_at, price) values (?, ?, ?, ?, ?)")
+                .unwrap();
+            let mut update = tx
+                .prepare("update price set updated_at = ?, price = ? where state = ? and station = ? and fuel = ?")
+                .unwrap();
+            for price in prices.current {
+                let key = (price.state as u8, price.station, price.fuel as u8);
+                let old: Option<Option<f64>> =
+                    select.query_row(key, |row| row.get(0)).optional().unwrap();
+                if let Some(old) = old {
+                    update
+                        .execute((now, price.price, key.0, key.1, key.2))
+                        .unwrap();
+                    if old == price.price {
+                        continue;
+                    }
+                } else {
+                    insert
+                        .execute((key.0, key.1, key.2, now, price.price))
+                        .unwrap();
+                }
+                history
+                    .execute((key.0, key.1, key.2, now, price.price))
+                    .unwrap();
+            }
+        }
+        tx.commit().unwrap();
+    }
+
+    /// 100k prices, 12,500 stations with every fuel, with `changed` of every
+    /// 1000 raised by `rise`.
+    fn synthetic(changed: u32, rise: f64) -> Prices {
+        let mut rows = Vec::new();
+        for station in 0..12_500 {
+            for (i, fuel) in Fuel::all().into_iter().enumerate() {
+                let n = station * 8 + i as u32;
+                let price = 180.0 + i as f64 + if n % 1000 < changed { rise } else { 0.0 };
+                rows.push((station, fuel, Some(price)));
+            }
+        }
+        feed(&rows)
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with cargo test --release store -- --ignored --nocapture"]
+    fn benchmark() {
+        let scenarios = [
+            ("first fetch", 0, 0.0),
+            ("unchanged", 0, 0.0),
+            ("1% changed", 10, 1.0),
+            ("10% changed", 100, 2.0),
+        ];
+        let (_old_db, old) = TempDb::new("bench-old");
+        let (_new_db, new) = TempDb::new("bench-new");
+        for conn in [&old, &new] {
+            conn.pragma_update(None, "journal_mode", "wal").unwrap();
+        }
+
+        for (i, (name, changed, rise)) in scenarios.into_iter().enumerate() {
+            let time = 1000 + i as u64;
+
+            let start = Instant::now();
+            row_by_row(&old, time, synthetic(changed, rise));
+            let old_time = start.elapsed();
+
+            let start = Instant::now();
+            prices(&new, &run(time), synthetic(changed, rise)).unwrap();
+            let new_time = start.elapsed();
+
+            println!("{name:>12}: row by row {old_time:>10.2?}, diff and upsert {new_time:>10.2?}");
+        }
+    }
+}
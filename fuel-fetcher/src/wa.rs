@@ -1,86 +1,97 @@
 use std::{collections::BTreeMap, thread::sleep, time::Duration};
 
 use anyhow::{bail, Result};
+use chrono::{DateTime, Days, FixedOffset, NaiveDate, TimeDelta, Utc};
 use geo::Point;
 use serde::Deserialize;
+use ureq::Agent;
 
-use crate::{CurrentPrice, Fuel, State, Station};
+use crate::{fuels::Codes, CurrentPrice, ForecastPrice, Prices, State, Station};
 
-pub const FUELS: [&str; 7] = ["ULP", "PUP", "DSL", "BDL", "LPG", "98R", "E85"];
+pub const FUELS: [&str; 8] = ["ULP", "PUP", "DSL", "BDL", "LPG", "98R", "E85", "E10"];
 
-pub fn prices() -> Result<Vec<CurrentPrice>> {
+/// Stations and their current and tomorrow's prices, from one request per
+/// product. There's no list of stations, so ignored products are still fetched
+/// to find the stations that only sell those.
+pub fn data(codes: &Codes) -> Result<Prices> {
     let agent = crate::agent();
+    let tomorrow = forecast_date(Utc::now());
 
-    let mut prices = Vec::new();
-    for fuel in FUELS {
-        let mut attempt = 0;
-        let data: Vec<RawStation> = loop {
-            match agent
-                .get(&format!(
-                    "https://www.fuelwatch.wa.gov.au/api/sites?fuelType={fuel}",
-                ))
-                .call()
-            {
-                Ok(x) => break x.into_json()?,
-                Err(ureq::Error::Status(500 | 503, _)) if attempt < 3 => {
-                    attempt += 1;
-                    eprintln!("Attempt {attempt} failed");
-                    sleep(Duration::from_secs(3));
-                    continue;
-                }
-                Err(e) => bail!(e),
-            }
-        };
-        let fuel = match fuel {
-            "ULP" => Fuel::Unleaded91,
-            "PUP" => Fuel::Unleaded95,
-            "DSL" => Fuel::Diesel,
-            "BDL" => Fuel::PremiumDiesel,
-            "LPG" => Fuel::LPG,
-            "98R" => Fuel::Unleaded98,
-            "E85" => Fuel::Ethanol85,
-            _ => unreachable!(),
-        };
-        for station in data {
-            prices.push(CurrentPrice {
+    let mut stations = BTreeMap::new();
+    let mut prices = Prices::default();
+    for code in FUELS {
+        let fuel = codes.get(code)?;
+        for station in fetch(&agent, code)? {
+            stations
+                .entry(station.id)
+                .or_insert_with(|| Point::new(station.address.longitude, station.address.latitude));
+
+            let Some(fuel) = fuel else {
+                continue;
+            };
+            prices.current.push(CurrentPrice {
                 state: State::WA,
                 station: station.id,
                 fuel,
                 price: station.product.price_today,
-            })
-        }
-    }
-    Ok(prices)
-}
-
-pub fn stations() -> Result<Vec<Station>> {
-    let agent = crate::agent();
-    let mut stations = BTreeMap::new();
-    for fuel in FUELS {
-        let data: Vec<RawStation> = agent
-            .get(&format!(
-                "https://www.fuelwatch.wa.gov.au/api/sites?fuelType={fuel}",
-            ))
-            .call()?
-            .into_json()?;
-        for station in data {
-            if !stations.contains_key(&station.id) {
-                stations.insert(
-                    station.id,
-                    Point::new(station.address.latitude, station.address.longitude),
-                );
+            });
+            if let Some(price) = station.product.price_tomorrow {
+                prices.forecast.push(ForecastPrice {
+                    state: State::WA,
+                    station: station.id,
+                    fuel,
+                    date: tomorrow,
+                    price,
+                });
             }
         }
     }
 
-    Ok(stations
+    prices.stations = stations
         .into_iter()
         .map(|(id, point)| Station {
             state: State::WA,
             id,
+            brand: None,
             point,
         })
-        .collect())
+        .collect();
+    Ok(prices)
+}
+
+/// The FuelWatch day `now` falls in. Days start at 6am perth time (utc+8, no
+/// daylight saving), so before 6am it's still the previous calendar day.
+pub fn trading_day(now: DateTime<Utc>) -> NaiveDate {
+    let perth = FixedOffset::east_opt(8 * 60 * 60).expect("hardcoded");
+    (now.with_timezone(&perth) - TimeDelta::hours(6)).date_naive()
+}
+
+/// The day `price_tomorrow` applies to. Tomorrow's prices are published by
+/// 2:30pm the day before, so before 6am "tomorrow" is still today's date.
+fn forecast_date(now: DateTime<Utc>) -> NaiveDate {
+    trading_day(now)
+        .checked_add_days(Days::new(1))
+        .expect("not the end of time")
+}
+
+fn fetch(agent: &Agent, code: &str) -> Result<Vec<RawStation>> {
+    let mut attempt = 0;
+    loop {
+        match agent
+            .get(&format!(
+                "https://www.fuelwatch.wa.gov.au/api/sites?fuelType={code}",
+            ))
+            .call()
+        {
+            Ok(x) => return Ok(x.into_json()?),
+            Err(ureq::Error::Status(500 | 503, _)) if attempt < 3 => {
+                attempt += 1;
+                eprintln!("Attempt {attempt} failed");
+                sleep(Duration::from_secs(3));
+            }
+            Err(e) => bail!(e),
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -100,4 +111,39 @@ struct Address {
 #[serde(rename_all = "camelCase")]
 struct Product {
     price_today: Option<f64>,
+    // missing until it's published in the afternoon
+    #[serde(default)]
+    price_tomorrow: Option<f64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn perth(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().to_utc()
+    }
+
+    #[test]
+    fn forecast_date_follows_fuelwatch_days() {
+        let date = |s| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+        // published in the afternoon for the next morning
+        assert_eq!(
+            forecast_date(perth("2024-03-10T15:00:00+08:00")),
+            date("2024-03-11")
+        );
+        // just after midnight it's still the day that started at 6am yesterday
+        assert_eq!(
+            forecast_date(perth("2024-03-11T00:05:00+08:00")),
+            date("2024-03-11")
+        );
+        assert_eq!(
+            forecast_date(perth("2024-03-11T05:59:59+08:00")),
+            date("2024-03-11")
+        );
+        assert_eq!(
+            forecast_date(perth("2024-03-11T06:00:00+08:00")),
+            date("2024-03-12")
+        );
+    }
 }
@@ -1,22 +1,25 @@
-use std::{
-    collections::BTreeMap,
-    fs::{self, File},
-    io::BufRead,
-    path::Path,
-};
-
-use anyhow::Result;
+use std::{collections::BTreeMap, env, fs::File, path::PathBuf, str::FromStr};
+
+use anyhow::{bail, Context, Result};
 use chrono::{DateTime, Utc};
 use glob::glob;
 use serde::Serialize;
 use typed_floats::tf64::NonNaN;
 
+// the same parser as fuel-fetcher, for this crate's `Fuel`
+#[path = "../../fuel-fetcher/src/fuels.rs"]
+mod fuels;
 mod nsw;
 mod nt;
+mod parquet_export;
+// the same parquet layout as fuel-fetcher, to be read together
+#[path = "../../fuel-fetcher/src/partition.rs"]
+mod partition;
 mod qld;
 mod wa;
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
 enum State {
     NSW,
     NT,
@@ -25,6 +28,15 @@ enum State {
 }
 
 impl State {
+    const fn as_str(&self) -> &'static str {
+        match self {
+            Self::NSW => "NSW",
+            Self::NT => "NT",
+            Self::QLD => "QLD",
+            Self::WA => "WA",
+        }
+    }
+
     fn slug(&self) -> &'static str {
         match self {
             Self::NSW => "nsw",
@@ -36,8 +48,24 @@ impl State {
 }
 
 fn main() -> Result<()> {
+    // [--fuels-file fuels.toml] [output], parquet partitions are written to
+    // output if given, see parquet_export.rs
+    let mut output = None;
+    let mut fuels_file = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "-f" | "--fuels-file" => {
+                fuels_file = Some(args.next().context("--fuels-file needs a path")?)
+            }
+            x if x.starts_with('-') => bail!("unknown option {x}"),
+            _ if output.is_some() => bail!("only one output directory can be given"),
+            _ => output = Some(PathBuf::from(arg)),
+        }
+    }
+    let codes: fuels::Codes = fuels::load(fuels_file.as_deref(), include_str!("../fuels.toml"))?;
     for state in [State::NSW, State::NT, State::QLD, State::WA] {
-        let mut records: BTreeMap<Site, Vec<Record>> = BTreeMap::new();
+        let mut records: BTreeMap<Site, Vec<(Fuel, Record)>> = BTreeMap::new();
         for path in glob(&format!("../../raw/{}/*.csv.zst", state.slug()))? {
             let path = path?;
             let data = zstd::decode_all(File::open(&path)?)?;
@@ -51,45 +79,33 @@ fn main() -> Result<()> {
                 State::WA => wa::parse(data)?,
             };
             if let Some(x) = output.first() {
-                println!("{} {path:?}", x.price.timestamp.date_naive().to_string());
+                println!("{} {path:?}", x.price.timestamp.date_naive());
             }
 
             for record in output {
-                let fuel = match &*record.price.fuel {
-                    "Diesel" | "DL" => Fuel::Diesel,
-                    "Premium Diesel" | "Brand Diesel" | "PDL" => Fuel::PremiumDiesel,
-                    "Bio Diesel 20" | "B20" => Fuel::Biodiesel,
-                    "E10" | "e10" | "Ethanol 94 (E10)" => Fuel::Ethanol10,
-                    "E85" | "e85" | "Ethanol 105 (E85)" => Fuel::Ethanol85,
-                    "LPG" => Fuel::LPG,
-                    "U91" | "Unleaded 91" | "Unleaded" | "ULP" | "OPAL" | "Low Aromatic Fuel" => {
-                        Fuel::Unleaded91
-                    }
-                    "P95" | "Premium 95" | "PULP 95/96 RON" | "PULP" => Fuel::Unleaded95,
-                    "P98" | "Premium 98" | "PULP 98 RON" | "98 RON" => Fuel::Unleaded98,
-
-                    // very few, appear to be errors
-                    "Liquefied natural gas" | "CNG" | "LNG" | "EV" | "P100" => continue,
-
-                    // phased out 2006
-                    "LRP" => continue,
-
-                    _x => {
-                        // println!("{_x}");
-                        continue;
-                    }
+                if !codes.contains(&record.price.fuel) {
+                    // println!("{}", record.price.fuel);
+                    continue;
+                }
+                let Some(fuel) = codes.get(&record.price.fuel)? else {
+                    continue;
                 };
 
-                if let Some(x) = records.get_mut(&record.site) {
-                    x.push(record.price);
-                } else {
-                    records.insert(record.site, vec![record.price]);
-                }
+                records
+                    .entry(record.site)
+                    .or_default()
+                    .push((fuel, record.price));
             }
 
             // eprintln!("{path:?} {}", records.len());
         }
 
+        if let Some(dir) = &output {
+            for path in parquet_export::export(dir, state, &records)? {
+                eprintln!("Wrote {path:?}");
+            }
+        }
+
         // for (site, prices) in records {
         //     println!("{}", serde_json::to_string(&OutputRecord { site, prices })?);
         // }
@@ -98,6 +114,8 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+#[derive(Debug, Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
 enum Fuel {
     Diesel,
     PremiumDiesel,
@@ -110,6 +128,46 @@ enum Fuel {
     Unleaded98,
 }
 
+impl Fuel {
+    const fn all() -> [Self; 9] {
+        use Fuel::*;
+        [
+            Diesel,
+            PremiumDiesel,
+            Biodiesel,
+            LPG,
+            Ethanol10,
+            Ethanol85,
+            Unleaded91,
+            Unleaded95,
+            Unleaded98,
+        ]
+    }
+
+    // same names as fuel-fetcher
+    const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Diesel => "Diesel",
+            Self::PremiumDiesel => "PremiumDiesel",
+            Self::Biodiesel => "Biodiesel",
+            Self::LPG => "LPG",
+            Self::Ethanol10 => "Ethanol10",
+            Self::Ethanol85 => "Ethanol85",
+            Self::Unleaded91 => "Unleaded91",
+            Self::Unleaded95 => "Unleaded95",
+            Self::Unleaded98 => "Unleaded98",
+        }
+    }
+}
+
+impl FromStr for Fuel {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::all().into_iter().find(|x| x.as_str() == s).ok_or(())
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Clone)]
 struct Site {
     id: Option<u64>,
@@ -0,0 +1,51 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use chrono::{Datelike, NaiveDate};
+
+use crate::{
+    partition::{self, Row},
+    Fuel, Record, Site, State,
+};
+
+// fuel-fetcher writes fetcher.parquet next to it
+const FILE: &str = "history.parquet";
+
+/// Writes one state's records under `dir` as `state=NSW/month=2024-03/history.parquet`,
+/// with UTC months. Partitions already written after their month ended are
+/// skipped, so only new ones (and the current month) are written again.
+/// Returns the partitions that were written.
+pub fn export(
+    dir: &Path,
+    state: State,
+    records: &BTreeMap<Site, Vec<(Fuel, Record)>>,
+) -> Result<Vec<PathBuf>> {
+    let mut months: HashMap<NaiveDate, Vec<Row>> = HashMap::new();
+    for (site, prices) in records {
+        for (fuel, record) in prices {
+            let month = record.timestamp.date_naive().with_day(1).expect("day 1");
+            months.entry(month).or_default().push(Row {
+                timestamp: record.timestamp,
+                station: site.id,
+                fuel: *fuel,
+                price: Some(record.price.into()),
+            });
+        }
+    }
+
+    let mut written = Vec::new();
+    for (month, mut rows) in months {
+        let path = partition::path(dir, state, month, FILE);
+        if partition::is_complete(&path, month)? {
+            continue;
+        }
+        rows.sort_by_key(|x| (x.timestamp, x.station, x.fuel as u8));
+        partition::write(&path, state, &rows)?;
+        written.push(path);
+    }
+    written.sort();
+    Ok(written)
+}